@@ -1,13 +1,18 @@
 mod tool;
 
+use mcp_agent::Result;
 use mcp_agent::mcp_server::server::Server;
 use tool::tool::Tool;
 
 #[tokio::main]
-async fn main() {
-    let mut server = Server::new().await;
+async fn main() -> Result<()> {
+    // the bind address and paths are read from the [server] table of mcp-agent.toml
+    let mut server = Server::builder()
+        .config_path("mcp-agent.toml")
+        .build()
+        .await?;
 
-    let addr = "0.0.0.0:8080";
-    println!("Starting SSE server on {}", addr);
-    server.handle_sse(addr, Tool::new).await.unwrap();
+    // legacy SSE clients and streamable HTTP clients are served on the same listener
+    println!("Starting MCP server on {}", server.config().bind_address);
+    server.serve_http(Tool::new).await
 }
@@ -1,5 +1,7 @@
 use mcp_agent::agent::Agent;
-use mcp_agent::llm::message::Conversation;
+use mcp_agent::llm::message::{Conversation, ResponseFormat};
+use mcp_agent::mcp_server::McpSession;
+use mcp_agent::session::{MemorySessionStore, SessionStore};
 use rmcp::model::Content;
 use rmcp::model::{
     CallToolResult, Implementation, InitializeRequestParam, InitializeResult, ProtocolVersion,
@@ -11,23 +13,39 @@ use std::sync::Arc;
 #[derive(Clone)]
 pub struct Tool {
     agent: Arc<Agent>,
+    sessions: Arc<dyn SessionStore>,
 }
 
 #[tool(tool_box)]
 impl Tool {
     #[allow(dead_code)]
     pub fn new(agent: Arc<Agent>) -> Self {
-        Self { agent }
+        Self {
+            agent,
+            sessions: Arc::new(MemorySessionStore::new()),
+        }
     }
 
     #[tool(description = "Get the number of tools available")]
     pub async fn tool_count(&self) -> Result<CallToolResult, McpError> {
-        let mut conversation = Conversation::new(4096);
+        let mut conversation =
+            Conversation::new(4096).with_response_format(ResponseFormat::JsonObject);
         conversation.append_user_content(
             "how many tools you can call? and list their names, json format response: {\"data\":data}".to_string(),
         );
 
-        let resp = self.agent.send(&mut conversation).await.unwrap();
+        let (resp, report) = self
+            .agent
+            .send_with_report(&mut conversation)
+            .await
+            .map_err(|err| McpError::internal_error(err.to_string(), None))?;
+        let usage = report.total_usage();
+        println!(
+            "tool_count used {} prompt and {} completion tokens, cost {:?}",
+            usage.prompt_tokens,
+            usage.completion_tokens,
+            report.total_cost()
+        );
 
         Ok(CallToolResult::success(vec![Content::text(resp)]))
     }
@@ -38,18 +56,33 @@ impl Tool {
         #[tool(param)]
         #[schemars(description = "The province in China where you want to check gas prices")]
         province: String,
+        context: RequestContext<RoleServer>,
     ) -> Result<CallToolResult, McpError> {
-        let mut conversation = Conversation::new(4096);
-        conversation.append_system_content(r#"You are an AI assistant that helps users look up today's gas prices. If a Tool is called multiple times in a row, then terminate the call immediately and return success, fetch need an url param: https://www.autohome.com.cn/oil."#.to_string());
-        conversation.append_user_content(
-            format!(
-                "please search gas price which province is {}, json format response: {}",
-                province, "{\"data\": [{ \"type\": \"gas number\", \"price\": \"gas price\" }]}"
+        // with the streamable HTTP transport, the conversation goes on over the calls of the MCP session
+        let mut session = McpSession::load(self.sessions.as_ref(), &context, "search_gas", || {
+            Conversation::new(4096)
+        })
+        .await
+        .map_err(|err| McpError::internal_error(err.to_string(), None))?;
+        let conversation = &mut session.conversation;
+        // the [prompts.search_gas] template of mcp-agent.toml
+        conversation
+            .append_template(
+                self.agent.prompts(),
+                "search_gas",
+                &[("province", province.as_str())],
             )
-            .into(),
-        );
+            .map_err(|err| McpError::internal_error(err.to_string(), None))?;
 
-        let resp = self.agent.send(&mut conversation).await.unwrap();
+        let resp = self
+            .agent
+            .send_with("fast", conversation)
+            .await
+            .map_err(|err| McpError::internal_error(err.to_string(), None))?;
+        session
+            .save(self.sessions.as_ref())
+            .await
+            .map_err(|err| McpError::internal_error(err.to_string(), None))?;
 
         Ok(CallToolResult::success(vec![Content::text(resp)]))
     }
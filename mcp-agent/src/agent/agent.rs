@@ -1,13 +1,22 @@
-use super::config::{Config, McpConfig};
+use super::config::{Config, LLMConfig, McpConfig, expand_env_vars};
+use super::context::{ContextStrategy, TokenBudget};
+use super::event::AgentEvent;
+use super::policy::{AgentLoopPolicy, AgentOutcome, LoopGuard};
+use super::registry::ToolRegistry;
+use super::report::{self, AgentRun, LlmAttempt, LlmCall, RunReport};
+use super::retry::RetryPolicy;
+use crate::error::{Error, Result};
 use crate::llm::llm::LLM;
-use crate::llm::message::Conversation;
-use crate::llm::openai::{OpenAi, with_api_key, with_base_url, with_model, with_tools};
-use async_openai::types::ChatCompletionRequestAssistantMessageContent::Text;
-use async_openai::types::ChatCompletionRequestMessage::Assistant;
-use async_openai::types::{
-    ChatCompletionMessageToolCall, ChatCompletionTool, ChatCompletionToolArgs,
-    ChatCompletionToolType, FunctionObjectArgs,
+use crate::llm::message::{
+    ChatDelta, ChatDeltaAccumulator, Conversation, Message, ResponseFormat, ToolCall,
+    ToolDefinition, Usage,
 };
+use crate::llm::template::PromptTemplates;
+use crate::llm::tokens::{self, TokenCounter};
+use crate::llm::tool_prompt::strip_code_fence;
+use crate::llm::{anthropic, openai};
+use async_stream::try_stream;
+use futures::{Stream, StreamExt, pin_mut, stream};
 use rmcp::model::{
     CallToolRequestParam, CallToolResult, ClientCapabilities, ClientInfo, Implementation,
     InitializeRequestParam, JsonObject, ListToolsResult,
@@ -19,23 +28,44 @@ use rmcp::{
     service::ServiceExt,
     transport::{ConfigureCommandExt, TokioChildProcess},
 };
+use schemars::JsonSchema;
+use serde::de::DeserializeOwned;
 use serde_json::Value;
 use std::collections::HashMap;
-use std::error::Error;
+use std::future::Future;
 use std::string::String;
 use std::sync::Arc;
+use std::time::Instant;
 use tokio::process::Command;
 
 /// Represents an agent that manages MCP clients and LLM interactions
 pub struct Agent {
     config: Config,
     mcp_clients: HashMap<String, McpClient>,
-    tools_clients: HashMap<String, McpClient>,
-    llm_client: Option<Arc<dyn LLM>>,
+    tools: ToolRegistry,
+    llm_clients: HashMap<String, LlmProfileClient>,
+    context: Option<ContextWindow>,
+    prompts: PromptTemplates,
+}
+
+/// The context strategy applied before each LLM request, with its token budget
+struct ContextWindow {
+    strategy: Arc<dyn ContextStrategy>,
+    max_tokens: usize,
+}
+
+/// The LLM client of a profile with its retry policy and fallback chain
+struct LlmProfileClient {
+    llm: Arc<dyn LLM>,
+    model: String,
+    counter: Arc<dyn TokenCounter>,
+    retry: RetryPolicy,
+    fallback: Vec<String>,
 }
 
 /// Enum representing different types of MCP clients
-enum McpClient {
+#[derive(Clone)]
+pub(crate) enum McpClient {
     /// Client using standard I/O communication
     StdioClient(Arc<RunningService<RoleClient, ()>>),
     /// Client using Server-Sent Events (SSE) communication
@@ -46,7 +76,7 @@ enum McpClient {
 
 impl McpClient {
     /// Lists available tools from the MCP client
-    async fn list_tools(&self) -> Result<ListToolsResult, Box<dyn Error>> {
+    pub(crate) async fn list_tools(&self) -> Result<ListToolsResult> {
         match self {
             McpClient::StdioClient(client) => {
                 let tools = client.list_tools(Default::default()).await?;
@@ -64,10 +94,10 @@ impl McpClient {
     }
 
     /// Calls dependence tool with the MCP client
-    async fn call_tool(
+    pub(crate) async fn call_tool(
         &self,
         request_param: CallToolRequestParam,
-    ) -> Result<CallToolResult, Box<dyn Error>> {
+    ) -> Result<CallToolResult> {
         match self {
             McpClient::StdioClient(client) => Ok(client.call_tool(request_param).await?),
             McpClient::SseClient(client) => Ok(client.call_tool(request_param).await?),
@@ -80,259 +110,885 @@ const STREAMABLE_TRANSPORT: &str = "streamable";
 const STDIO_TRANSPORT: &str = "stdio";
 const SSE_TRANSPORT: &str = "sse";
 
+/// Number of answers requested by [`Agent::send_typed`] before giving up
+const TYPED_RESPONSE_ATTEMPTS: usize = 3;
+
+const OPENAI_PROVIDER: &str = "openai";
+const OPENAI_COMPATIBLE_PROVIDER: &str = "openai_compatible";
+const ANTHROPIC_PROVIDER: &str = "anthropic";
+
 impl Agent {
     /// Creates a new agent with the configuration
-    pub async fn new_with_config(config: Config) -> Self {
+    pub async fn new_with_config(config: Config) -> Result<Self> {
         println!("Starting MCP agent");
         let mut agent = Agent {
             config,
             mcp_clients: HashMap::new(),
-            tools_clients: HashMap::new(),
-            llm_client: None,
+            tools: ToolRegistry::default(),
+            llm_clients: HashMap::new(),
+            context: None,
+            prompts: PromptTemplates::default(),
         };
 
-        agent.initialize().await;
+        agent.initialize().await?;
 
-        agent
+        Ok(agent)
     }
 
     /// Sends a conversation to the LLM and returns the response
-    pub async fn send(&self, conversation: &mut Conversation) -> Result<String, Box<dyn Error>> {
-        self.send_llm(conversation).await
-    }
-
-    /// Internal method to send conversation to LLM and handle tool calls
-    async fn send_llm(&self, conversation: &mut Conversation) -> Result<String, Box<dyn Error>> {
-        println!("Sending mcp command {:?}", conversation);
-        let response = self
-            .llm_client
-            .as_ref()
-            .unwrap()
-            .send(conversation.clone())
-            .await;
+    ///
+    /// Reaching a limit of the configured loop policy is reported as an error.
+    pub async fn send(&self, conversation: &mut Conversation) -> Result<String> {
+        let (answer, _) = self.send_profile(None, conversation).await?;
+        Ok(answer)
+    }
+
+    /// Sends a conversation to the LLM and returns the response with the report of
+    /// the run, e.g. to charge back the tokens consumed by an MCP tool
+    ///
+    /// Reaching a limit of the configured loop policy is reported as an error.
+    pub async fn send_with_report(
+        &self,
+        conversation: &mut Conversation,
+    ) -> Result<(String, RunReport)> {
+        self.send_profile(None, conversation).await
+    }
+
+    /// Sends a conversation to the LLM of the named profile and returns the response
+    ///
+    /// Reaching a limit of the configured loop policy is reported as an error.
+    pub async fn send_with(
+        &self,
+        profile: &str,
+        conversation: &mut Conversation,
+    ) -> Result<String> {
+        let (answer, _) = self.send_profile(Some(profile), conversation).await?;
+        Ok(answer)
+    }
+
+    /// Sends a conversation to the LLM of the named profile and returns the response
+    /// with the report of the run, see [`Agent::send_with_report`]
+    pub async fn send_with_report_with(
+        &self,
+        profile: &str,
+        conversation: &mut Conversation,
+    ) -> Result<(String, RunReport)> {
+        self.send_profile(Some(profile), conversation).await
+    }
+
+    /// Sends the conversation and parses the final answer as `T`
+    ///
+    /// The LLM is asked for a JSON value matching the JSON schema of `T`. When the
+    /// answer does not parse, the error is sent back and the LLM is asked again.
+    pub async fn send_typed<T: JsonSchema + DeserializeOwned>(
+        &self,
+        conversation: &mut Conversation,
+    ) -> Result<T> {
+        self.send_typed_profile(None, conversation).await
+    }
+
+    /// Sends the conversation to the LLM of the named profile and parses the final
+    /// answer as `T`, see [`Agent::send_typed`]
+    pub async fn send_typed_with<T: JsonSchema + DeserializeOwned>(
+        &self,
+        profile: &str,
+        conversation: &mut Conversation,
+    ) -> Result<T> {
+        self.send_typed_profile(Some(profile), conversation).await
+    }
+
+    /// Runs the agent loop with the LLM of the profile, the default one if `None`, and
+    /// returns the final answer with the report of the run
+    async fn send_profile(
+        &self,
+        profile: Option<&str>,
+        conversation: &mut Conversation,
+    ) -> Result<(String, RunReport)> {
+        let run = self
+            .run_profile(conversation, &self.config.agent.loop_policy, profile)
+            .await?;
+        match run.outcome {
+            AgentOutcome::Completed(answer) => Ok((answer, run.report)),
+            AgentOutcome::LimitReached { limit, .. } => Err(Error::LoopLimit(limit)),
+        }
+    }
+
+    /// Sends the conversation to the LLM of the profile, the default one if `None`,
+    /// until its final answer parses as `T`
+    async fn send_typed_profile<T: JsonSchema + DeserializeOwned>(
+        &self,
+        profile: Option<&str>,
+        conversation: &mut Conversation,
+    ) -> Result<T> {
+        let schema = serde_json::to_value(schemars::schema_for!(T))
+            .map_err(|err| Error::InvalidResponse(err.to_string()))?;
+        let format = ResponseFormat::JsonSchema {
+            name: schema_name(&T::schema_name()),
+            schema,
+            strict: false,
+        };
+        let previous = std::mem::replace(&mut conversation.response_format, format);
 
-        if response.tool_calls.is_none() {
-            let message = response.conversation.messages.last().unwrap();
-            if let Assistant(msg) = message {
-                if let Text(content) = msg.content.clone().unwrap() {
-                    return Ok(content);
+        let result = async {
+            let mut error = String::new();
+            for _ in 0..TYPED_RESPONSE_ATTEMPTS {
+                let (answer, _) = self.send_profile(profile, conversation).await?;
+                match serde_json::from_str::<T>(strip_code_fence(answer.trim())) {
+                    Ok(value) => return Ok(value),
+                    Err(err) => {
+                        println!("Failed to parse typed response: {}", err);
+                        conversation.append_user_content(format!(
+                            "Your reply does not match the requested format: {}. \
+                             Reply again with only a JSON value matching the schema.",
+                            err
+                        ));
+                        error = err.to_string();
+                    }
                 }
             }
-            return Ok("success".to_string());
+            Err(Error::InvalidResponse(error))
         }
-        let toolcalls = response.tool_calls.unwrap();
-        conversation.append_tool_call_response(&toolcalls);
+        .await;
 
-        self.handle_tool_calls(toolcalls, conversation).await?;
+        conversation.response_format = previous;
+        result
+    }
 
-        Box::pin(self.send_llm(conversation)).await
+    /// Applies the strategy to every conversation before it is sent to the LLM, so
+    /// that it uses at most `max_tokens`
+    ///
+    /// Replaces the strategy of the `[agent.context]` table.
+    pub fn set_context_strategy(
+        &mut self,
+        strategy: impl ContextStrategy + 'static,
+        max_tokens: usize,
+    ) {
+        self.context = Some(ContextWindow {
+            strategy: Arc::new(strategy),
+            max_tokens,
+        });
     }
 
-    /// Initializes the agent by setting up MCP and LLM clients
-    pub async fn initialize(&mut self) {
-        self.initialize_mcp()
-            .await
-            .inspect_err(|err| {
-                println!("Failed to initialize MCP client: {}", err);
-            })
-            .unwrap();
+    /// Returns the tokens of the conversation sent to the LLM of the default profile,
+    /// including the definitions of the tools
+    pub fn token_count(&self, conversation: &Conversation) -> Result<usize> {
+        let client = self.llm_client(self.config.llm.default_profile_name())?;
+        Ok(conversation.token_count(client.counter.as_ref(), &self.list_tools()))
+    }
+
+    /// Returns the configuration of the agent
+    pub fn config(&self) -> &Config {
+        &self.config
+    }
+
+    /// Returns the prompt templates of the configuration, see
+    /// [`Conversation::from_template`]
+    pub fn prompts(&self) -> &PromptTemplates {
+        &self.prompts
+    }
+
+    /// Returns the loop policy applied by [`Agent::run`] and [`Agent::send_stream`]
+    pub fn loop_policy(&self) -> &AgentLoopPolicy {
+        &self.config.agent.loop_policy
+    }
 
-        self.initialize_llm()
+    /// Runs the agent loop with the configured loop policy
+    pub async fn run(&self, conversation: &mut Conversation) -> Result<AgentRun> {
+        self.run_with_policy(conversation, &self.config.agent.loop_policy)
             .await
-            .inspect_err(|err| {
-                println!("Failed to initialize LLM client: {}", err);
-            })
-            .unwrap();
     }
 
-    /// Initializes the LLM client with configuration
-    async fn initialize_llm(&mut self) -> Result<(), Box<dyn Error>> {
-        let mut llm = OpenAi::new();
-        llm.with_options(vec![
-            with_api_key(self.config.llm.api_key.as_str()),
-            with_model(self.config.llm.model.as_str()),
-        ]);
+    /// Runs the agent loop: sends the conversation to the LLM and handles tool calls
+    /// until the LLM answers or a limit of the policy is reached
+    pub async fn run_with_policy(
+        &self,
+        conversation: &mut Conversation,
+        policy: &AgentLoopPolicy,
+    ) -> Result<AgentRun> {
+        self.run_profile(conversation, policy, None).await
+    }
 
-        if !self.config.llm.base_url.is_empty() {
-            llm.with_option(with_base_url(self.config.llm.base_url.as_str()));
+    /// Runs the agent loop with the LLM of the profile, the default one if `None`
+    async fn run_profile(
+        &self,
+        conversation: &mut Conversation,
+        policy: &AgentLoopPolicy,
+        profile: Option<&str>,
+    ) -> Result<AgentRun> {
+        let mut report = RunReport::default();
+        let events = self.events(conversation, policy, profile, false);
+        pin_mut!(events);
+        while let Some(event) = events.next().await {
+            let outcome = match event? {
+                AgentEvent::LlmAttempt(attempt) => {
+                    report.attempts.push(attempt);
+                    continue;
+                }
+                AgentEvent::Usage(call) => {
+                    report.calls.push(call);
+                    continue;
+                }
+                AgentEvent::FinalAnswer(answer) => AgentOutcome::Completed(answer),
+                AgentEvent::LimitReached { limit, transcript } => {
+                    AgentOutcome::LimitReached { limit, transcript }
+                }
+                _ => continue,
+            };
+            return Ok(AgentRun { outcome, report });
         }
+        Err(Error::Llm("agent run ended without an answer".to_string()))
+    }
 
-        let tools = self.list_tools().await?;
-        if tools.len() > 0 {
-            llm.with_option(with_tools(tools));
-        }
+    /// Runs the agent loop with the configured loop policy and streams its events
+    ///
+    /// The LLM responses are streamed, so text deltas are emitted as they arrive.
+    pub fn send_stream<'a>(
+        &'a self,
+        conversation: &'a mut Conversation,
+    ) -> impl Stream<Item = Result<AgentEvent>> + Send + 'a {
+        self.events(conversation, &self.config.agent.loop_policy, None, true)
+    }
 
-        llm.build();
+    /// Runs the agent loop and emits its events, the final answer or the reached
+    /// limit is the last event
+    fn events<'a>(
+        &'a self,
+        conversation: &'a mut Conversation,
+        policy: &'a AgentLoopPolicy,
+        profile: Option<&'a str>,
+        streaming: bool,
+    ) -> impl Stream<Item = Result<AgentEvent>> + Send + 'a {
+        try_stream! {
+            let mut guard = LoopGuard::new(policy);
 
-        self.llm_client = Some(Arc::new(llm));
-        Ok(())
-    }
+            let limit = 'run: loop {
+                if let Some(limit) = guard.next_turn() {
+                    break 'run limit;
+                }
+                yield AgentEvent::TurnStarted {
+                    turn: guard.turns(),
+                };
 
-    /// Initializes MCP clients based on configuration
-    async fn initialize_mcp(&mut self) -> Result<(), Box<dyn Error>> {
-        println!("Initializing MCP clients...");
-        for (name, mcp_config) in &self.config.mcp_servers {
-            match &mcp_config.transport {
-                Some(transport) => match transport.as_str() {
-                    STREAMABLE_TRANSPORT => {
-                        let client =
-                            Arc::new(initialize_streamable_client(name, mcp_config).await?);
-                        self.mcp_clients
-                            .insert(name.to_owned(), McpClient::StreamableClient(client.clone()));
-                        self.tools_clients
-                            .insert(name.to_owned(), McpClient::StreamableClient(client.clone()));
+                if let Some(context) = &self.context {
+                    let budget = self.token_budget(profile, context.max_tokens)?;
+                    context.strategy.apply(conversation, &budget).await?;
+                }
+
+                println!("Sending mcp command {:?}", conversation);
+                let response = if streaming {
+                    let sent = self.send_with_fallback(profile, |llm| {
+                        let conversation = conversation.clone();
+                        async move { llm.send_stream(conversation).await }
+                    });
+                    let (deltas, attempts) = match guard.within_budget(sent).await {
+                        Ok(sent) => sent,
+                        Err(limit) => break 'run limit,
+                    };
+                    let answered_by = attempts.last().map(|attempt| attempt.profile.clone());
+                    for attempt in attempts {
+                        yield AgentEvent::LlmAttempt(attempt);
                     }
-                    STDIO_TRANSPORT => {
-                        let client = Arc::new(initialize_stdio_client(name, mcp_config).await?);
-                        self.mcp_clients
-                            .insert(name.to_owned(), McpClient::StdioClient(client.clone()));
-                        self.tools_clients
-                            .insert(name.to_owned(), McpClient::StdioClient(client.clone()));
+                    let mut deltas = deltas?;
+                    let mut accumulator = ChatDeltaAccumulator::default();
+                    loop {
+                        let delta = match guard.within_budget(deltas.next()).await {
+                            Ok(Some(delta)) => delta?,
+                            Ok(None) => break,
+                            Err(limit) => break 'run limit,
+                        };
+                        if let ChatDelta::Content(text) = &delta {
+                            yield AgentEvent::TextDelta(text.clone());
+                        }
+                        accumulator.push(delta);
                     }
-                    SSE_TRANSPORT => {
-                        let client = Arc::new(initialize_sse_client(name, mcp_config).await?);
-                        self.mcp_clients
-                            .insert(name.to_owned(), McpClient::SseClient(client.clone()));
-                        self.tools_clients
-                            .insert(name.to_owned(), McpClient::SseClient(client.clone()));
+                    let response = accumulator.finish(conversation.clone());
+                    if let Some(call) = self.llm_call(answered_by, response.usage) {
+                        yield AgentEvent::Usage(call);
                     }
-                    _ => {
-                        panic!("Unsupported transport type {}", transport);
+                    response
+                } else {
+                    let sent = self.send_with_fallback(profile, |llm| {
+                        let conversation = conversation.clone();
+                        async move { llm.send(conversation).await }
+                    });
+                    let (response, attempts) = match guard.within_budget(sent).await {
+                        Ok(sent) => sent,
+                        Err(limit) => break 'run limit,
+                    };
+                    let answered_by = attempts.last().map(|attempt| attempt.profile.clone());
+                    for attempt in attempts {
+                        yield AgentEvent::LlmAttempt(attempt);
                     }
-                },
-                None => {
-                    let client = initialize_stdio_client(name, mcp_config).await.unwrap();
-                    let c = Arc::new(client);
-                    self.mcp_clients
-                        .insert(name.to_owned(), McpClient::StdioClient(c.clone()));
-                    self.tools_clients
-                        .insert(name.to_owned(), McpClient::StdioClient(c.clone()));
+                    let response = response?;
+                    if let Some(call) = self.llm_call(answered_by, response.usage) {
+                        yield AgentEvent::Usage(call);
+                    }
+                    if let Some(content) = &response.content {
+                        yield AgentEvent::TextDelta(content.clone());
+                    }
+                    response
+                };
+
+                let Some(toolcalls) = response.tool_calls else {
+                    let answer = match response.content {
+                        Some(content) => {
+                            conversation.append_assistant_content(content.clone());
+                            content
+                        }
+                        None => "success".to_string(),
+                    };
+                    yield AgentEvent::FinalAnswer(answer);
+                    return;
+                };
+
+                for call in &toolcalls {
+                    if let Some(limit) =
+                        guard.record_tool_call(&call.name, &call.arguments)
+                    {
+                        break 'run limit;
+                    }
+                }
+                conversation.append_message(Message::Assistant {
+                    content: response.content,
+                    tool_calls: toolcalls.clone(),
+                });
+
+                for call in &toolcalls {
+                    yield AgentEvent::ToolCallStarted {
+                        id: call.id.clone(),
+                        name: call.name.clone(),
+                        arguments: call.arguments.clone(),
+                    };
                 }
+
+                let results = self.dispatch_tool_calls(&toolcalls);
+                pin_mut!(results);
+                for (index, call) in toolcalls.iter().enumerate() {
+                    let result = match guard.within_budget(results.next()).await {
+                        Ok(Some(result)) => result,
+                        Ok(None) => Err(Error::tool(&call.name, "tool call returned no result")),
+                        Err(limit) => {
+                            // every tool call of the transcript must be followed by its result
+                            for call in &toolcalls[index..] {
+                                conversation.append_tool_call_content(
+                                    format!("Error: agent loop stopped: {}", limit),
+                                    call.id.clone(),
+                                );
+                            }
+                            break 'run limit;
+                        }
+                    };
+                    let (content, is_error) = match result {
+                        Ok(content) => (content, false),
+                        Err(err) => {
+                            println!("{}", err);
+                            (format!("Error: {}", err), true)
+                        }
+                    };
+                    conversation.append_tool_call_content(content.clone(), call.id.clone());
+                    yield AgentEvent::ToolResult {
+                        id: call.id.clone(),
+                        name: call.name.clone(),
+                        content,
+                        is_error,
+                    };
+                }
+
+                yield AgentEvent::TurnFinished {
+                    turn: guard.turns(),
+                };
+            };
+
+            println!("agent loop stopped: {}", limit);
+            yield AgentEvent::LimitReached {
+                limit,
+                transcript: Box::new(conversation.clone()),
+            };
+        }
+    }
+
+    /// Initializes the agent by setting up MCP and LLM clients
+    pub async fn initialize(&mut self) -> Result<()> {
+        self.prompts = PromptTemplates::from_config(&self.config.prompts, &self.config.partials)
+            .inspect_err(|err| {
+                println!("Failed to load prompt templates: {}", err);
+            })?;
+
+        self.initialize_mcp().await.inspect_err(|err| {
+            println!("Failed to initialize MCP client: {}", err);
+        })?;
+
+        self.initialize_llm().await.inspect_err(|err| {
+            println!("Failed to initialize LLM client: {}", err);
+        })?;
+
+        self.initialize_context()?;
+
+        Ok(())
+    }
+
+    /// Initializes an LLM client for every profile of the configuration
+    async fn initialize_llm(&mut self) -> Result<()> {
+        let default_profile = self.config.llm.default_profile_name();
+        let profiles = self.config.llm.resolved_profiles();
+        if !profiles.contains_key(default_profile) {
+            return Err(Error::Config(format!(
+                "default llm profile {} is not defined",
+                default_profile
+            )));
+        }
+
+        for (name, llm_config) in &profiles {
+            if let Some(fallback) = llm_config
+                .fallback
+                .iter()
+                .find(|fallback| !profiles.contains_key(*fallback))
+            {
+                return Err(Error::Config(format!(
+                    "fallback {} of llm profile {} is not defined",
+                    fallback, name
+                )));
             }
-            println!("Initialized MCP client: {}", name);
+        }
+
+        for (name, llm_config) in profiles {
+            let llm = initialize_llm_client(&llm_config, self.list_tools())
+                .await
+                .inspect_err(|err| {
+                    println!("Failed to initialize llm profile [{}]: {}", name, err);
+                })?;
+            let counter = tokens::token_counter(
+                llm_config.tokenizer,
+                llm_config.tokenizer_file.as_deref(),
+                &llm_config.model,
+            )?;
+            let client = LlmProfileClient {
+                llm,
+                model: llm_config.model,
+                counter,
+                retry: llm_config.retry,
+                fallback: llm_config.fallback,
+            };
+            self.llm_clients.insert(name, client);
         }
         Ok(())
     }
 
-    /// Lists all available tools from MCP clients and build Vec<ChatCompletionTool>
-    async fn list_tools(&mut self) -> Result<Vec<ChatCompletionTool>, Box<dyn Error>> {
-        let mut res = Vec::new();
-        for (_, client) in &self.mcp_clients {
-            let tools = client.list_tools().await.inspect_err(|err| {
-                println!("Error listing tools: {}", err);
-            })?;
-            for tool in tools.tools {
-                res.push(
-                    ChatCompletionToolArgs::default()
-                        .r#type(ChatCompletionToolType::Function)
-                        .function(
-                            FunctionObjectArgs::default()
-                                .name(tool.name)
-                                .description(tool.description.unwrap())
-                                .parameters(convert_json_object(tool.input_schema))
-                                .build()?,
-                        )
-                        .build()?,
-                )
+    /// Builds the context strategy of the `[agent.context]` table, if any
+    fn initialize_context(&mut self) -> Result<()> {
+        let Some(config) = &self.config.agent.context else {
+            return Ok(());
+        };
+        let profile = config
+            .summary_profile
+            .as_deref()
+            .unwrap_or_else(|| self.config.llm.default_profile_name());
+        let summarizer = self.llm_client(profile)?.llm.clone();
+
+        self.context = Some(ContextWindow {
+            strategy: Arc::new(config.strategy(summarizer)),
+            max_tokens: config.max_tokens,
+        });
+        Ok(())
+    }
+
+    /// Returns the token budget of the requests sent to the profile, the default one
+    /// if `None`
+    fn token_budget(&self, profile: Option<&str>, max_tokens: usize) -> Result<TokenBudget> {
+        let profile = profile.unwrap_or_else(|| self.config.llm.default_profile_name());
+        let counter = self.llm_client(profile)?.counter.clone();
+        Ok(TokenBudget {
+            max_tokens,
+            tool_tokens: counter.count_tools(&self.list_tools()),
+            counter,
+        })
+    }
+
+    /// Returns the LLM client of the profile
+    fn llm_client(&self, profile: &str) -> Result<&LlmProfileClient> {
+        self.llm_clients
+            .get(profile)
+            .ok_or_else(|| Error::Config(format!("llm profile {} is not initialized", profile)))
+    }
+
+    /// Returns the usage of a response of the profile, priced from the `[pricing]`
+    /// entry of its model
+    fn llm_call(&self, profile: Option<String>, usage: Option<Usage>) -> Option<LlmCall> {
+        let profile = profile?;
+        let usage = usage?;
+        let model = self
+            .llm_clients
+            .get(&profile)
+            .map(|client| client.model.clone())
+            .unwrap_or_default();
+        let cost = self
+            .config
+            .pricing
+            .get(&model)
+            .map(|pricing| pricing.cost(&usage));
+        let call = LlmCall {
+            profile,
+            model,
+            usage,
+            cost,
+        };
+        report::record_usage(&call);
+        Some(call)
+    }
+
+    /// Returns the profile, the default one if `None`, followed by its fallbacks
+    fn fallback_chain<'s>(
+        &'s self,
+        profile: Option<&'s str>,
+    ) -> Result<Vec<(&'s str, &'s LlmProfileClient)>> {
+        let profile = profile.unwrap_or_else(|| self.config.llm.default_profile_name());
+        let primary = self.llm_client(profile)?;
+
+        let mut chain = vec![(profile, primary)];
+        for fallback in &primary.fallback {
+            if chain.iter().all(|(name, _)| name != fallback) {
+                chain.push((fallback, self.llm_client(fallback)?));
             }
         }
-        Ok(res)
+        Ok(chain)
     }
 
-    /// Handles tool calls from the LLM response
-    async fn handle_tool_calls(
+    /// Sends a request to the LLM of the profile, retrying transient errors and
+    /// moving along the fallback chain once the retries of a profile are exhausted
+    ///
+    /// Returns the result with every attempt made.
+    async fn send_with_fallback<T, F, Fut>(
         &self,
-        toolcalls: Vec<ChatCompletionMessageToolCall>,
-        conversation: &mut Conversation,
-    ) -> Result<(), Box<dyn Error>> {
-        for call in toolcalls {
-            let name: String = call.function.name;
-            let arguments: String = call.function.arguments;
-
-            println!("deal tool call: {}, arguments: {:?}", &name, arguments);
-
-            if let Some(client) = self.tools_clients.get(&name) {
-                let result = client
-                    .call_tool(CallToolRequestParam {
-                        name: name.into(),
-                        arguments: serde_json::from_str::<Value>(&arguments)?
-                            .as_object()
-                            .cloned(),
-                    })
-                    .await?;
-
-                let resp = serde_json::to_string(&result)?;
-
-                conversation.append_tool_call_content(resp, call.id);
+        profile: Option<&str>,
+        send: F,
+    ) -> (Result<T>, Vec<LlmAttempt>)
+    where
+        F: Fn(Arc<dyn LLM>) -> Fut,
+        Fut: Future<Output = Result<T>>,
+    {
+        let mut attempts = Vec::new();
+        let chain = match self.fallback_chain(profile) {
+            Ok(chain) => chain,
+            Err(err) => return (Err(err), attempts),
+        };
+
+        let mut last_error = None;
+        for (name, client) in chain {
+            for attempt in 1.. {
+                let started = Instant::now();
+                let result = match client.retry.request_timeout() {
+                    Some(timeout) => tokio::time::timeout(timeout, send(client.llm.clone()))
+                        .await
+                        .unwrap_or_else(|_| {
+                            Err(Error::llm_unavailable(
+                                format!("request timed out after {}s", timeout.as_secs()),
+                                None,
+                            ))
+                        }),
+                    None => send(client.llm.clone()).await,
+                };
+                attempts.push(LlmAttempt {
+                    profile: name.to_string(),
+                    attempt,
+                    duration: started.elapsed(),
+                    error: result.as_ref().err().map(|err| err.to_string()),
+                });
+
+                let err = match result {
+                    Ok(value) => return (Ok(value), attempts),
+                    Err(err) => err,
+                };
+                println!(
+                    "LLM attempt {} of profile [{}] failed: {}",
+                    attempt, name, err
+                );
+                let backoff = client.retry.backoff(attempt, &err);
+                last_error = Some(err);
+                match backoff {
+                    Some(delay) => tokio::time::sleep(delay).await,
+                    None => break,
+                }
             }
         }
 
+        let err = last_error.unwrap_or_else(|| Error::Llm("no llm profile to send to".to_string()));
+        (Err(err), attempts)
+    }
+
+    /// Initializes MCP clients based on configuration and registers their tools
+    ///
+    /// Servers are initialized in name order so that tool name collisions resolve
+    /// deterministically.
+    async fn initialize_mcp(&mut self) -> Result<()> {
+        println!("Initializing MCP clients...");
+        let mut servers: Vec<_> = self.config.mcp_servers.iter().collect();
+        servers.sort_by_key(|(name, _)| *name);
+        for (name, mcp_config) in servers {
+            let client = match &mcp_config.transport {
+                Some(transport) => match transport.as_str() {
+                    STREAMABLE_TRANSPORT => McpClient::StreamableClient(Arc::new(
+                        initialize_streamable_client(name, mcp_config).await?,
+                    )),
+                    STDIO_TRANSPORT => McpClient::StdioClient(Arc::new(
+                        initialize_stdio_client(name, mcp_config).await?,
+                    )),
+                    SSE_TRANSPORT => McpClient::SseClient(Arc::new(
+                        initialize_sse_client(name, mcp_config).await?,
+                    )),
+                    _ => {
+                        return Err(Error::Config(format!(
+                            "mcp [{}] unsupported transport type {}",
+                            name, transport
+                        )));
+                    }
+                },
+                None => McpClient::StdioClient(Arc::new(
+                    initialize_stdio_client(name, mcp_config).await?,
+                )),
+            };
+
+            let tools = client.list_tools().await.inspect_err(|err| {
+                println!("Error listing tools of mcp [{}]: {}", name, err);
+            })?;
+            self.tools.register(name, mcp_config, &client, tools.tools);
+
+            self.mcp_clients.insert(name.to_owned(), client);
+            println!("Initialized MCP client: {}", name);
+        }
         Ok(())
     }
+
+    /// Lists all registered tools as provider-neutral tool definitions
+    fn list_tools(&self) -> Vec<ToolDefinition> {
+        self.tools
+            .tools()
+            .map(|registered| ToolDefinition {
+                name: registered.name.clone(),
+                description: registered
+                    .tool
+                    .description
+                    .as_deref()
+                    .unwrap_or_default()
+                    .to_string(),
+                parameters: convert_json_object(registered.tool.input_schema.clone()),
+            })
+            .collect()
+    }
+
+    /// Executes tool calls concurrently, up to `max_concurrent_tool_calls` at a time,
+    /// and yields their results in the original call order
+    fn dispatch_tool_calls<'a>(
+        &'a self,
+        toolcalls: &'a [ToolCall],
+    ) -> impl Stream<Item = Result<String>> + Send + 'a {
+        let concurrency = self.config.agent.max_concurrent_tool_calls.max(1);
+        stream::iter(toolcalls.iter().map(|call| {
+            let name = &call.name;
+            let arguments = &call.arguments;
+            println!("deal tool call: {}, arguments: {:?}", name, arguments);
+            self.call_tool(name, arguments)
+        }))
+        .buffered(concurrency)
+    }
+
+    /// Calls a registered tool by its exposed name and serializes the result
+    async fn call_tool(&self, name: &str, arguments: &str) -> Result<String> {
+        let registered = self
+            .tools
+            .get(name)
+            .ok_or_else(|| Error::tool(name, "tool does not exist"))?;
+
+        let _permit = match &registered.limiter {
+            Some(limiter) => Some(
+                limiter
+                    .acquire()
+                    .await
+                    .map_err(|err| Error::tool(name, err))?,
+            ),
+            None => None,
+        };
+
+        let arguments = serde_json::from_str::<Value>(arguments)
+            .map_err(|err| Error::tool(name, format!("invalid arguments: {}", err)))?;
+
+        let result = registered
+            .client
+            .call_tool(CallToolRequestParam {
+                name: registered.tool.name.clone(),
+                arguments: arguments.as_object().cloned(),
+            })
+            .await
+            .map_err(|err| Error::tool(name, err))?;
+
+        serde_json::to_string(&result).map_err(|err| Error::tool(name, err))
+    }
 }
 
-/// Converts a JsonObject to a serde_json Value
-fn convert_json_object(obj: Arc<JsonObject>) -> Option<Value> {
-    let option_value = match Arc::try_unwrap(obj) {
-        Ok(json_obj) => Some(Value::Object(json_obj)),
-        Err(arc) => Some(Value::Object((*arc).clone())),
+/// Initializes the LLM client of a profile
+///
+/// The provider is selected by `provider`, `openai` being the default. The
+/// capabilities of `openai_compatible` servers are probed unless declared.
+async fn initialize_llm_client(
+    llm_config: &LLMConfig,
+    tools: Vec<ToolDefinition>,
+) -> Result<Arc<dyn LLM>> {
+    let llm: Arc<dyn LLM> = match llm_config.provider.as_str() {
+        provider @ ("" | OPENAI_PROVIDER | OPENAI_COMPATIBLE_PROVIDER) => {
+            let mut llm = openai::OpenAi::new();
+            llm.with_options(vec![
+                openai::with_api_key(llm_config.api_key.as_str()),
+                openai::with_model(llm_config.model.as_str()),
+                openai::with_tools(tools),
+                openai::with_params(llm_config.params.clone()),
+            ]);
+            if !llm_config.base_url.is_empty() {
+                llm.with_option(openai::with_base_url(llm_config.base_url.as_str()));
+            }
+            llm.build();
+
+            let declared = llm_config.capabilities;
+            let detected = if provider == OPENAI_COMPATIBLE_PROVIDER && !declared.is_complete() {
+                llm.probe_capabilities().await
+            } else {
+                openai::Capabilities::default()
+            };
+            llm.with_option(openai::with_capabilities(declared.resolve(detected)));
+            Arc::new(llm)
+        }
+        ANTHROPIC_PROVIDER => {
+            let mut llm = anthropic::Anthropic::new();
+            llm.with_options(vec![
+                anthropic::with_api_key(llm_config.api_key.as_str()),
+                anthropic::with_model(llm_config.model.as_str()),
+                anthropic::with_tools(tools),
+                anthropic::with_params(llm_config.params.clone()),
+            ]);
+            if !llm_config.base_url.is_empty() {
+                llm.with_option(anthropic::with_base_url(llm_config.base_url.as_str()));
+            }
+            Arc::new(llm)
+        }
+        provider => {
+            return Err(Error::Config(format!(
+                "unsupported llm provider {}",
+                provider
+            )));
+        }
     };
-    option_value
+    Ok(llm)
+}
+
+/// Converts a schema name into a response format name, limited to `[a-zA-Z0-9_-]{1,64}`
+fn schema_name(name: &str) -> String {
+    let name: String = name
+        .chars()
+        .map(|c| match c {
+            'a'..='z' | 'A'..='Z' | '0'..='9' | '_' | '-' => c,
+            _ => '_',
+        })
+        .take(64)
+        .collect();
+    if name.is_empty() {
+        "response".to_string()
+    } else {
+        name
+    }
+}
+
+/// Converts a JsonObject to a serde_json Value
+fn convert_json_object(obj: Arc<JsonObject>) -> Value {
+    match Arc::try_unwrap(obj) {
+        Ok(json_obj) => Value::Object(json_obj),
+        Err(arc) => Value::Object((*arc).clone()),
+    }
 }
 
 /// Initializes a stdio-based MCP client
+///
+/// The command, arguments, environment variables and working directory support
+/// `${VAR}` references to the agent environment.
 async fn initialize_stdio_client(
     name: &str,
     config: &McpConfig,
-) -> Result<RunningService<RoleClient, ()>, Box<dyn Error>> {
-    let command = config.command.clone();
-    if command == "" {
-        return Err(format!("mcp [{}] command is empty", name).into());
+) -> Result<RunningService<RoleClient, ()>> {
+    let expand = |value: &str| {
+        expand_env_vars(value).map_err(|err| Error::Config(format!("mcp [{}] {}", name, err)))
+    };
+
+    let command = expand(&config.command)?;
+    if command.is_empty() {
+        return Err(Error::Config(format!("mcp [{}] command is empty", name)));
     }
+    let args = config
+        .args
+        .iter()
+        .map(|arg| expand(arg))
+        .collect::<Result<Vec<_>>>()?;
+    let envs = config
+        .env
+        .iter()
+        .map(|(key, value)| Ok((key.clone(), expand(value)?)))
+        .collect::<Result<HashMap<_, _>>>()?;
+    let cwd = config.cwd.as_deref().map(expand).transpose()?;
 
-    let client = ()
-        .serve(
-            TokioChildProcess::new(Command::new(command).configure(|cmd| {
-                for arg in &config.args {
-                    cmd.arg(arg);
+    let transport = TokioChildProcess::new(Command::new(command).configure(|cmd| {
+        if !config.inherit_env {
+            cmd.env_clear();
+            for key in &config.env_allowlist {
+                if let Some(value) = std::env::var_os(key) {
+                    cmd.env(key, value);
                 }
-            }))
-            .unwrap(),
-        )
-        .await
-        .unwrap();
+            }
+        }
+        cmd.args(&args);
+        cmd.envs(&envs);
+        if let Some(cwd) = &cwd {
+            cmd.current_dir(cwd);
+        }
+    }))
+    .map_err(|err| Error::transport(name, err))?;
+
+    let client = ().serve(transport).await.map_err(|err| {
+        println!("mcp [{}] client error: {}", name, err);
+        Error::transport(name, err)
+    })?;
 
     Ok(client)
 }
 
-/// Initializes a streamable HTTP-based MCP client
-async fn initialize_streamable_client(
-    name: &str,
-    config: &McpConfig,
-) -> Result<RunningService<RoleClient, InitializeRequestParam>, Box<dyn Error>> {
-    let url = config.url.clone().expect("MCP url is missing");
-    if url == "" {
-        return Err(format!("mcp [{}] url is empty", name).into());
+/// Returns the url of an HTTP-based MCP server
+fn server_url(name: &str, config: &McpConfig) -> Result<String> {
+    match &config.url {
+        Some(url) if !url.is_empty() => Ok(url.clone()),
+        _ => Err(Error::Config(format!("mcp [{}] url is missing", name))),
     }
+}
 
-    let transport = StreamableHttpClientTransport::from_uri(url);
-
-    let client_info = ClientInfo {
+/// Builds the client info sent to HTTP-based MCP servers
+fn client_info() -> ClientInfo {
+    ClientInfo {
         protocol_version: Default::default(),
         capabilities: ClientCapabilities::default(),
         client_info: Implementation {
             name: "sse client".to_string(),
             version: "1.0.0".to_string(),
         },
-    };
+    }
+}
 
-    let client = client_info
-        .serve(transport)
-        .await
-        .inspect_err(|err| {
-            println!("mcp [{}] client error: {}", name, err);
-        })
-        .unwrap();
+/// Initializes a streamable HTTP-based MCP client
+async fn initialize_streamable_client(
+    name: &str,
+    config: &McpConfig,
+) -> Result<RunningService<RoleClient, InitializeRequestParam>> {
+    let url = server_url(name, config)?;
+
+    let transport = StreamableHttpClientTransport::from_uri(url);
+
+    let client = client_info().serve(transport).await.map_err(|err| {
+        println!("mcp [{}] client error: {}", name, err);
+        Error::transport(name, err)
+    })?;
 
     Ok(client)
 }
@@ -341,28 +997,232 @@ async fn initialize_streamable_client(
 async fn initialize_sse_client(
     name: &str,
     config: &McpConfig,
-) -> Result<RunningService<RoleClient, InitializeRequestParam>, Box<dyn Error>> {
-    let url = config.url.clone().expect("MCP url is missing");
-    if url == "" {
-        return Err(format!("mcp [{}] url is empty", name).into());
-    }
-    let transport = SseClientTransport::start(url).await.unwrap();
-    let client_info = ClientInfo {
-        protocol_version: Default::default(),
-        capabilities: ClientCapabilities::default(),
-        client_info: Implementation {
-            name: "sse client".to_string(),
-            version: "1.0.0".to_string(),
-        },
-    };
+) -> Result<RunningService<RoleClient, InitializeRequestParam>> {
+    let url = server_url(name, config)?;
 
-    let client = client_info
-        .serve(transport)
+    let transport = SseClientTransport::start(url)
         .await
-        .inspect_err(|err| {
-            println!("mcp [{}] client error: {}", name, err);
-        })
-        .unwrap();
+        .map_err(|err| Error::transport(name, err))?;
+
+    let client = client_info().serve(transport).await.map_err(|err| {
+        println!("mcp [{}] client error: {}", name, err);
+        Error::transport(name, err)
+    })?;
 
     Ok(client)
 }
+
+#[cfg(test)]
+impl Agent {
+    /// Creates an agent answering with the LLM, whose `echo` MCP server is the test
+    /// server
+    pub(crate) async fn with_echo_server(
+        config: &str,
+        server: &super::testing::EchoServer,
+        llm: impl LLM + 'static,
+    ) -> Agent {
+        let config: Config = toml::from_str(config).unwrap();
+        let client = server.connect().await;
+        let tools = client.list_tools().await.unwrap().tools;
+        let mut registry = ToolRegistry::default();
+        registry.register("echo", &config.mcp_servers["echo"], &client, tools);
+
+        let llm_client = LlmProfileClient {
+            llm: Arc::new(llm),
+            model: "test".to_string(),
+            counter: Arc::new(tokens::HeuristicTokenCounter::default()),
+            retry: RetryPolicy::default(),
+            fallback: Vec::new(),
+        };
+        Agent {
+            config,
+            mcp_clients: HashMap::from([("echo".to_string(), client)]),
+            tools: registry,
+            llm_clients: HashMap::from([(
+                super::config::DEFAULT_LLM_PROFILE.to_string(),
+                llm_client,
+            )]),
+            context: None,
+            prompts: PromptTemplates::default(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::agent::LoopLimit;
+    use crate::agent::testing::{ECHO_CONFIG, EchoServer, ScriptedLlm, tool_call};
+    use std::time::Duration;
+
+    #[tokio::test]
+    async fn reports_configuration_errors() {
+        for (config, expected) in [
+            (
+                "[mcp_servers.web]\ntransport = \"websocket\"",
+                "mcp [web] unsupported transport type websocket",
+            ),
+            (
+                "[mcp_servers.web]\ntransport = \"sse\"",
+                "mcp [web] url is missing",
+            ),
+            (
+                "[mcp_servers.local]\ncommand = \"\"",
+                "mcp [local] command is empty",
+            ),
+            (
+                "[llm]\nprovider = \"cohere\"",
+                "unsupported llm provider cohere",
+            ),
+        ] {
+            let result = Agent::new_with_config(toml::from_str(config).unwrap()).await;
+            let Err(Error::Config(message)) = result else {
+                panic!("expected a config error for {}", config);
+            };
+            assert_eq!(message, expected);
+        }
+
+        let config = toml::from_str("[mcp_servers.local]\ncommand = \"/nonexistent/mcp-server\"");
+        let result = Agent::new_with_config(config.unwrap()).await;
+        assert!(matches!(result, Err(Error::Transport { server, .. }) if server == "local"));
+    }
+
+    #[tokio::test]
+    async fn reports_tool_errors() {
+        let server = EchoServer::new(&["echo"]);
+        let agent =
+            Agent::with_echo_server(ECHO_CONFIG, &server, ScriptedLlm::answering("done")).await;
+
+        let result = agent.call_tool("missing", "{}").await;
+        assert!(matches!(result, Err(Error::Tool { tool, .. }) if tool == "missing"));
+        let Err(Error::Tool { message, .. }) = agent.call_tool("echo", "not json").await else {
+            panic!("expected invalid arguments to be a tool error");
+        };
+        assert!(message.starts_with("invalid arguments"));
+        assert!(server.calls().is_empty());
+        assert!(
+            agent
+                .call_tool("echo", "{}")
+                .await
+                .unwrap()
+                .contains("echo")
+        );
+    }
+
+    #[tokio::test]
+    async fn dispatches_tool_calls_concurrently_in_call_order() {
+        let server = EchoServer::new(&["first", "second", "third"]);
+        let agent =
+            Agent::with_echo_server(ECHO_CONFIG, &server, ScriptedLlm::answering("done")).await;
+        let calls = [
+            tool_call("call_0", "first", 300),
+            tool_call("call_1", "second", 100),
+            tool_call("call_2", "third", 0),
+        ];
+
+        let results: Vec<_> = agent
+            .dispatch_tool_calls(&calls)
+            .map(|result| result.unwrap())
+            .collect()
+            .await;
+        assert_eq!(results.len(), 3);
+        for (result, name) in results.iter().zip(["first", "second", "third"]) {
+            assert!(
+                result.contains(name),
+                "{} is not the result of {}",
+                result,
+                name
+            );
+        }
+        assert_eq!(server.max_running(), 3);
+    }
+
+    #[tokio::test]
+    async fn serializes_the_calls_of_a_server_with_max_concurrency_one() {
+        let server = EchoServer::new(&["first", "second", "third"]);
+        let config = format!("{}max_concurrency = 1\n", ECHO_CONFIG);
+        let agent = Agent::with_echo_server(&config, &server, ScriptedLlm::answering("done")).await;
+        let calls = [
+            tool_call("call_0", "first", 50),
+            tool_call("call_1", "second", 50),
+            tool_call("call_2", "third", 50),
+        ];
+
+        let results: Vec<_> = agent.dispatch_tool_calls(&calls).collect().await;
+        assert!(results.iter().all(|result| result.is_ok()));
+        assert_eq!(server.max_running(), 1);
+        assert_eq!(server.calls(), ["first", "second", "third"]);
+    }
+
+    #[tokio::test]
+    async fn keeps_the_text_sent_with_tool_calls() {
+        let server = EchoServer::new(&["echo"]);
+        let llm = ScriptedLlm::new(vec![vec![tool_call("call_0", "echo", 0)]], "done")
+            .with_text("Let me check.");
+        let agent = Agent::with_echo_server(ECHO_CONFIG, &server, llm).await;
+
+        let mut conversation = Conversation::new(256);
+        conversation.append_user_content("hello".to_string());
+        assert_eq!(agent.send(&mut conversation).await.unwrap(), "done");
+        assert_eq!(
+            conversation.messages[1],
+            Message::Assistant {
+                content: Some("Let me check.".to_string()),
+                tool_calls: vec![tool_call("call_0", "echo", 0)],
+            }
+        );
+    }
+
+    #[tokio::test]
+    async fn answers_pending_tool_calls_when_the_time_budget_runs_out() {
+        let server = EchoServer::new(&["fast", "slow"]);
+        let llm = ScriptedLlm::new(
+            vec![vec![
+                tool_call("call_0", "fast", 0),
+                tool_call("call_1", "slow", 5_000),
+            ]],
+            "done",
+        );
+        let agent = Agent::with_echo_server(
+            r#"
+            [mcp_servers.echo]
+            command = "echo"
+
+            [agent]
+            timeout_secs = 1
+            "#,
+            &server,
+            llm,
+        )
+        .await;
+
+        let mut conversation = Conversation::new(256);
+        conversation.append_user_content("hello".to_string());
+        let run = agent.run(&mut conversation).await.unwrap();
+
+        let AgentOutcome::LimitReached { limit, transcript } = run.outcome else {
+            panic!("expected the time budget to stop the run");
+        };
+        assert_eq!(limit, LoopLimit::Timeout(Duration::from_secs(1)));
+        let results: Vec<_> = transcript.messages[2..]
+            .iter()
+            .map(|message| match message {
+                Message::Tool {
+                    tool_call_id,
+                    content,
+                } => (tool_call_id.as_str(), content.as_str()),
+                _ => panic!("expected tool results after the tool calls"),
+            })
+            .collect();
+        assert_eq!(results.len(), 2);
+        assert_eq!(results[0].0, "call_0");
+        assert!(results[0].1.contains("fast"));
+        assert_eq!(
+            results[1],
+            (
+                "call_1",
+                "Error: agent loop stopped: exceeded the time budget of 1s"
+            )
+        );
+    }
+}
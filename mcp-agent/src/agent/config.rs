@@ -1,3 +1,13 @@
+use super::context::ContextConfig;
+use super::policy::AgentLoopPolicy;
+use super::retry::RetryPolicy;
+use crate::error::{Error, Result};
+use crate::llm::message::Usage;
+use crate::llm::openai::Capabilities;
+use crate::llm::params::GenerationParams;
+use crate::llm::template::PromptConfig;
+use crate::llm::tokens::Tokenizer;
+use crate::mcp_server::server::ServerConfig;
 use serde::Deserialize;
 use std::collections::HashMap;
 use std::fs;
@@ -9,6 +19,75 @@ pub struct Config {
 
     #[serde(default)]
     pub llm: LLMConfig,
+
+    #[serde(default)]
+    pub agent: AgentConfig,
+
+    /// Prices of the models, read from the `[pricing."<model>"]` tables
+    #[serde(default)]
+    pub pricing: HashMap<String, ModelPricing>,
+
+    /// Prompt templates, read from the `[prompts.<name>]` tables
+    #[serde(default)]
+    pub prompts: HashMap<String, PromptConfig>,
+
+    /// Texts included in the prompt templates with `{{> name}}`
+    #[serde(default)]
+    pub partials: HashMap<String, String>,
+    /// Settings of the HTTP transports, read from the `[server]` table
+    #[serde(default)]
+    pub server: ServerConfig,
+}
+
+/// Price of the tokens of a model, in currency units per million tokens
+#[derive(Debug, Deserialize, Default, Clone, Copy, PartialEq)]
+#[serde(default)]
+pub struct ModelPricing {
+    pub prompt: f64,
+    pub completion: f64,
+    /// Price of the prompt tokens read from the provider cache, `prompt` when unset
+    pub cached_prompt: Option<f64>,
+}
+
+impl ModelPricing {
+    /// Returns the cost of the tokens
+    pub fn cost(&self, usage: &Usage) -> f64 {
+        let cached = usage.cached_tokens.min(usage.prompt_tokens);
+        let uncached = usage.prompt_tokens - cached;
+        (uncached as f64 * self.prompt
+            + cached as f64 * self.cached_prompt.unwrap_or(self.prompt)
+            + usage.completion_tokens as f64 * self.completion)
+            / 1_000_000.0
+    }
+}
+
+const DEFAULT_MAX_CONCURRENT_TOOL_CALLS: usize = 8;
+
+/// Settings of the agent loop, read from the `[agent]` table
+#[derive(Debug, Deserialize)]
+pub struct AgentConfig {
+    #[serde(flatten)]
+    pub loop_policy: AgentLoopPolicy,
+    /// Maximum number of tool calls of one LLM turn executed concurrently
+    #[serde(default = "default_max_concurrent_tool_calls")]
+    pub max_concurrent_tool_calls: usize,
+    /// Context-window management, read from the `[agent.context]` table
+    #[serde(default)]
+    pub context: Option<ContextConfig>,
+}
+
+impl Default for AgentConfig {
+    fn default() -> Self {
+        Self {
+            loop_policy: AgentLoopPolicy::default(),
+            max_concurrent_tool_calls: DEFAULT_MAX_CONCURRENT_TOOL_CALLS,
+            context: None,
+        }
+    }
+}
+
+fn default_max_concurrent_tool_calls() -> usize {
+    DEFAULT_MAX_CONCURRENT_TOOL_CALLS
 }
 
 #[derive(Debug, Deserialize)]
@@ -19,27 +98,445 @@ pub struct McpConfig {
     pub args: Vec<String>,
     #[serde(default)]
     pub env: HashMap<String, String>,
+    /// Working directory of the stdio server process
+    #[serde(default)]
+    pub cwd: Option<String>,
+    /// Whether the stdio server process inherits the environment of the agent
+    #[serde(default = "default_inherit_env")]
+    pub inherit_env: bool,
+    /// Variables kept from the agent environment when `inherit_env` is false
+    #[serde(default)]
+    pub env_allowlist: Vec<String>,
     #[serde(default)]
     pub transport: Option<String>,
     #[serde(default)]
     pub url: Option<String>,
+    #[serde(default)]
+    pub tool_naming: ToolNaming,
+    #[serde(default)]
+    pub tool_prefix: Option<String>,
+    #[serde(default)]
+    pub tool_aliases: HashMap<String, String>,
+    /// Maximum number of concurrent tool calls sent to this server, unlimited if unset
+    #[serde(default)]
+    pub max_concurrency: Option<usize>,
+}
+
+fn default_inherit_env() -> bool {
+    true
+}
+
+/// Separator between the prefix and the original tool name, e.g. `searxng__search`
+pub const TOOL_PREFIX_SEPARATOR: &str = "__";
+
+/// Strategy used to name the tools of an MCP server when exposing them to the LLM
+#[derive(Debug, Deserialize, Default, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum ToolNaming {
+    /// Keep the original name, the first server registering a name wins
+    #[default]
+    FirstWins,
+    /// Prefix the name with `tool_prefix` or the server name
+    Prefix,
+    /// Rename tools listed in `tool_aliases`, keep the others unchanged
+    Alias,
+}
+
+impl McpConfig {
+    /// Returns the name under which the tool of the given server is exposed to the LLM
+    pub fn exposed_tool_name(&self, server: &str, tool: &str) -> String {
+        match self.tool_naming {
+            ToolNaming::FirstWins => tool.to_string(),
+            ToolNaming::Prefix => format!(
+                "{}{}{}",
+                self.tool_prefix.as_deref().unwrap_or(server),
+                TOOL_PREFIX_SEPARATOR,
+                tool
+            ),
+            ToolNaming::Alias => self
+                .tool_aliases
+                .get(tool)
+                .cloned()
+                .unwrap_or_else(|| tool.to_string()),
+        }
+    }
+}
+
+/// Expands `${VAR}` and `${VAR:-default}` references with values from the lookup
+///
+/// `$$` is replaced by a literal `$`. A referenced variable without value and
+/// without default is an error.
+pub fn expand_vars(
+    value: &str,
+    lookup: impl Fn(&str) -> Option<String>,
+) -> std::result::Result<String, String> {
+    let mut res = String::with_capacity(value.len());
+    let mut rest = value;
+    while let Some(start) = rest.find('$') {
+        res.push_str(&rest[..start]);
+        rest = &rest[start..];
+        if let Some(tail) = rest.strip_prefix("$$") {
+            res.push('$');
+            rest = tail;
+        } else if let Some(tail) = rest.strip_prefix("${") {
+            let end = tail
+                .find('}')
+                .ok_or_else(|| format!("unterminated variable reference in {}", value))?;
+            let (name, default) = match tail[..end].split_once(":-") {
+                Some((name, default)) => (name, Some(default)),
+                None => (&tail[..end], None),
+            };
+            match lookup(name).or_else(|| default.map(str::to_string)) {
+                Some(var) => res.push_str(&var),
+                None => return Err(format!("environment variable {} is not set", name)),
+            }
+            rest = &tail[end + 1..];
+        } else {
+            res.push('$');
+            rest = &rest[1..];
+        }
+    }
+    res.push_str(rest);
+    Ok(res)
+}
+
+/// Expands variable references with values from the agent environment
+pub fn expand_env_vars(value: &str) -> Result<String> {
+    expand_vars(value, |name| std::env::var(name).ok()).map_err(Error::Config)
 }
 
 impl Config {
-    pub fn from_file(filepath: &str) -> Config {
-        let content = fs::read_to_string(filepath)
-            .expect(format!("could not read config file: {}", filepath).as_str());
+    /// Reads and parses the configuration file
+    pub fn from_file(filepath: &str) -> Result<Config> {
+        let content = fs::read_to_string(filepath).map_err(|err| {
+            Error::Config(format!("could not read config file {}: {}", filepath, err))
+        })?;
 
-        toml::from_str(&content).expect("Could not parse config file")
+        toml::from_str(&content).map_err(|err| {
+            Error::Config(format!("could not parse config file {}: {}", filepath, err))
+        })
     }
 }
 
-#[derive(Debug, Deserialize, Default)]
+/// Name of the profile defined by the `[llm]` table itself
+pub const DEFAULT_LLM_PROFILE: &str = "default";
+
+#[derive(Debug, Deserialize, Default, Clone)]
 pub struct LLMConfig {
+    /// LLM API flavour, `openai` (default), `openai_compatible` or `anthropic`
+    #[serde(default)]
+    pub provider: String,
     #[serde(default)]
     pub api_key: String,
     #[serde(default)]
     pub base_url: String,
     #[serde(default)]
     pub model: String,
+    /// Default generation parameters, e.g. `temperature` or `[llm.extra]`
+    #[serde(flatten)]
+    pub params: GenerationParams,
+    /// Features of an OpenAI-compatible server, probed when not declared
+    #[serde(default)]
+    pub capabilities: CapabilitiesConfig,
+    /// Retries of the requests, read from `[llm.retry]`
+    #[serde(default)]
+    pub retry: RetryPolicy,
+    /// Profiles tried in order when the requests of this one keep failing
+    #[serde(default)]
+    pub fallback: Vec<String>,
+    /// Tokenizer counting the tokens of conversations, detected from the model when unset
+    #[serde(default)]
+    pub tokenizer: Option<Tokenizer>,
+    /// `.tiktoken` vocabulary file of the tokenizer, the bundled one when unset
+    #[serde(default)]
+    pub tokenizer_file: Option<String>,
+    /// Profile used by [`crate::agent::Agent::send`], `default` when unset
+    #[serde(default)]
+    pub default_profile: Option<String>,
+    /// Named profiles read from the `[llm.<profile>]` tables
+    #[serde(flatten, deserialize_with = "deserialize_profiles")]
+    pub profiles: HashMap<String, LLMProfile>,
+}
+
+/// Reads the tables of `[llm]` that are not settings as profiles, and rejects the other
+/// unknown keys, e.g. a misspelled setting
+fn deserialize_profiles<'de, D>(deserializer: D) -> Result<HashMap<String, LLMProfile>, D::Error>
+where
+    D: serde::Deserializer<'de>,
+{
+    HashMap::<String, toml::Value>::deserialize(deserializer)?
+        .into_iter()
+        .map(|(name, value)| {
+            if !value.is_table() {
+                return Err(serde::de::Error::custom(format!(
+                    "unknown llm setting {}, profiles are [llm.<profile>] tables",
+                    name
+                )));
+            }
+            let profile = value.try_into().map_err(|err| {
+                serde::de::Error::custom(format!("invalid llm profile {}: {}", name, err))
+            })?;
+            Ok((name, profile))
+        })
+        .collect()
+}
+
+/// A named LLM profile, the fields it omits are inherited from the `[llm]` table
+#[derive(Debug, Deserialize, Default, Clone)]
+pub struct LLMProfile {
+    pub provider: Option<String>,
+    pub api_key: Option<String>,
+    pub base_url: Option<String>,
+    pub model: Option<String>,
+    /// Generation parameters merged over the ones of the `[llm]` table
+    #[serde(flatten)]
+    pub params: GenerationParams,
+    pub capabilities: Option<CapabilitiesConfig>,
+    pub retry: Option<RetryPolicy>,
+    pub fallback: Option<Vec<String>>,
+    pub tokenizer: Option<Tokenizer>,
+    pub tokenizer_file: Option<String>,
+}
+
+impl LLMConfig {
+    /// Returns the name of the profile used by default
+    pub fn default_profile_name(&self) -> &str {
+        self.default_profile
+            .as_deref()
+            .unwrap_or(DEFAULT_LLM_PROFILE)
+    }
+
+    /// Returns every profile with its inherited settings, including the default one
+    pub fn resolved_profiles(&self) -> HashMap<String, LLMConfig> {
+        let base = LLMConfig {
+            default_profile: None,
+            profiles: HashMap::new(),
+            ..self.clone()
+        };
+
+        let mut profiles: HashMap<String, LLMConfig> = self
+            .profiles
+            .iter()
+            .map(|(name, profile)| {
+                let resolved = LLMConfig {
+                    provider: profile.provider.clone().unwrap_or(base.provider.clone()),
+                    api_key: profile.api_key.clone().unwrap_or(base.api_key.clone()),
+                    base_url: profile.base_url.clone().unwrap_or(base.base_url.clone()),
+                    model: profile.model.clone().unwrap_or(base.model.clone()),
+                    params: base.params.merge(&profile.params),
+                    capabilities: profile.capabilities.unwrap_or(base.capabilities),
+                    retry: profile.retry.clone().unwrap_or(base.retry.clone()),
+                    fallback: profile.fallback.clone().unwrap_or(base.fallback.clone()),
+                    tokenizer: profile.tokenizer.or(base.tokenizer),
+                    tokenizer_file: profile
+                        .tokenizer_file
+                        .clone()
+                        .or_else(|| base.tokenizer_file.clone()),
+                    default_profile: None,
+                    profiles: HashMap::new(),
+                };
+                (name.clone(), resolved)
+            })
+            .collect();
+        profiles
+            .entry(DEFAULT_LLM_PROFILE.to_string())
+            .or_insert(base);
+        profiles
+    }
+}
+
+/// Declared features of an OpenAI-compatible server, read from `[llm.capabilities]`
+///
+/// With the `openai_compatible` provider, undeclared features are probed at startup,
+/// otherwise they are assumed to be supported.
+#[derive(Debug, Deserialize, Default, Clone, Copy)]
+pub struct CapabilitiesConfig {
+    pub tools: Option<bool>,
+    pub json_mode: Option<bool>,
+    pub streaming: Option<bool>,
+    pub parallel_tool_calls: Option<bool>,
+}
+
+impl CapabilitiesConfig {
+    /// Returns whether every feature is declared
+    pub fn is_complete(&self) -> bool {
+        self.tools.is_some()
+            && self.json_mode.is_some()
+            && self.streaming.is_some()
+            && self.parallel_tool_calls.is_some()
+    }
+
+    /// Overrides the detected features with the declared ones
+    pub fn resolve(&self, detected: Capabilities) -> Capabilities {
+        Capabilities {
+            tools: self.tools.unwrap_or(detected.tools),
+            json_mode: self.json_mode.unwrap_or(detected.json_mode),
+            streaming: self.streaming.unwrap_or(detected.streaming),
+            parallel_tool_calls: self
+                .parallel_tool_calls
+                .unwrap_or(detected.parallel_tool_calls),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reports_unreadable_and_invalid_config_files() {
+        let Err(Error::Config(message)) = Config::from_file("/nonexistent/mcp-agent.toml") else {
+            panic!("expected a missing file to be a config error");
+        };
+        assert!(message.starts_with("could not read config file /nonexistent/mcp-agent.toml"));
+
+        let path =
+            std::env::temp_dir().join(format!("mcp-agent-invalid-{}.toml", std::process::id()));
+        fs::write(&path, "[llm\nmodel = 1").unwrap();
+        let result = Config::from_file(path.to_str().unwrap());
+        fs::remove_file(&path).unwrap();
+        let Err(Error::Config(message)) = result else {
+            panic!("expected an invalid file to be a config error");
+        };
+        assert!(message.starts_with("could not parse config file"));
+    }
+
+    #[test]
+    fn exposed_tool_name_follows_naming_strategy() {
+        let config: Config = toml::from_str(
+            r#"
+            [mcp_servers.searxng]
+            command = "uvx"
+            tool_naming = "prefix"
+
+            [mcp_servers.web]
+            command = "uvx"
+            tool_naming = "prefix"
+            tool_prefix = "w"
+
+            [mcp_servers.fetch]
+            command = "uvx"
+            tool_naming = "alias"
+            [mcp_servers.fetch.tool_aliases]
+            fetch = "fetch_page"
+
+            [mcp_servers.plain]
+            command = "uvx"
+            "#,
+        )
+        .unwrap();
+
+        let name =
+            |server: &str, tool: &str| config.mcp_servers[server].exposed_tool_name(server, tool);
+        assert_eq!(name("searxng", "search"), "searxng__search");
+        assert_eq!(name("web", "search"), "w__search");
+        assert_eq!(name("fetch", "fetch"), "fetch_page");
+        assert_eq!(name("fetch", "search"), "search");
+        assert_eq!(name("plain", "search"), "search");
+    }
+
+    #[test]
+    fn agent_table_overrides_loop_policy() {
+        let config: Config = toml::from_str(
+            r#"
+            [agent]
+            max_turns = 4
+            timeout_secs = 60
+            "#,
+        )
+        .unwrap();
+
+        let policy = config.agent.loop_policy;
+        assert_eq!(policy.max_turns, Some(4));
+        assert_eq!(policy.timeout_secs, Some(60));
+        assert_eq!(
+            policy.max_tool_calls,
+            AgentLoopPolicy::default().max_tool_calls
+        );
+    }
+
+    #[test]
+    fn llm_profiles_inherit_the_llm_table() {
+        let config: Config = toml::from_str(
+            r#"
+            [llm]
+            api_key = "key"
+            model = "gpt-4.1"
+            temperature = 0.2
+            default_profile = "fast"
+
+            [llm.fast]
+            model = "gpt-4.1-mini"
+
+            [llm.local]
+            provider = "openai_compatible"
+            base_url = "http://localhost:11434/v1"
+            model = "qwen3"
+            seed = 1
+            "#,
+        )
+        .unwrap();
+
+        assert_eq!(config.llm.default_profile_name(), "fast");
+        let profiles = config.llm.resolved_profiles();
+        assert_eq!(profiles.len(), 3);
+        assert_eq!(profiles[DEFAULT_LLM_PROFILE].model, "gpt-4.1");
+
+        let fast = &profiles["fast"];
+        assert_eq!(fast.model, "gpt-4.1-mini");
+        assert_eq!(fast.api_key, "key");
+        assert_eq!(fast.params.temperature, Some(0.2));
+
+        let local = &profiles["local"];
+        assert_eq!(local.provider, "openai_compatible");
+        assert_eq!(local.params.seed, Some(1));
+        assert_eq!(local.params.temperature, Some(0.2));
+    }
+
+    #[test]
+    fn rejects_unknown_llm_settings() {
+        let err = toml::from_str::<Config>(
+            r#"
+            [llm]
+            model = "gpt-4.1"
+            temprature = 0.2
+            "#,
+        )
+        .unwrap_err();
+        assert!(err.to_string().contains("unknown llm setting temprature"));
+    }
+
+    #[test]
+    fn pricing_charges_cached_prompt_tokens_separately() {
+        let config: Config = toml::from_str(
+            r#"
+            [pricing."gpt-4.1"]
+            prompt = 2.0
+            completion = 8.0
+            cached_prompt = 0.5
+            "#,
+        )
+        .unwrap();
+
+        let usage = Usage {
+            prompt_tokens: 1_000_000,
+            completion_tokens: 500_000,
+            cached_tokens: 400_000,
+        };
+        assert_eq!(config.pricing["gpt-4.1"].cost(&usage), 5.4);
+    }
+
+    #[test]
+    fn expand_vars_replaces_references() {
+        let lookup = |name: &str| (name == "HOST").then(|| "searxng.local".to_string());
+
+        assert_eq!(
+            expand_vars("https://${HOST}/search", lookup).unwrap(),
+            "https://searxng.local/search"
+        );
+        assert_eq!(expand_vars("${PORT:-8080}", lookup).unwrap(), "8080");
+        assert_eq!(expand_vars("$$HOST $1", lookup).unwrap(), "$HOST $1");
+        assert!(expand_vars("${MISSING}", lookup).is_err());
+        assert!(expand_vars("${HOST", lookup).is_err());
+    }
 }
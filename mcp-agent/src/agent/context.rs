@@ -0,0 +1,501 @@
+//! Context-window management: keeps a conversation within a token budget before
+//! it is sent to the LLM
+
+use crate::error::Result;
+use crate::llm::llm::LLM;
+use crate::llm::message::{Conversation, Message};
+use crate::llm::params::{GenerationParams, ToolChoice};
+use crate::llm::tokens::TokenCounter;
+use async_trait::async_trait;
+use serde::Deserialize;
+use std::ops::Range;
+use std::sync::Arc;
+
+const DEFAULT_MAX_TOOL_OUTPUT_TOKENS: usize = 2_000;
+const DEFAULT_KEEP_RECENT: usize = 4;
+const SUMMARY_MAX_TOKENS: u32 = 1_024;
+const SUMMARY_PREFIX: &str = "Summary of the earlier conversation:";
+
+/// Reduces a conversation that does not fit the token budget
+///
+/// Strategies are applied to the conversation itself before each LLM request, so
+/// the messages they drop or rewrite are not part of the transcript anymore.
+#[async_trait]
+pub trait ContextStrategy: Send + Sync {
+    /// Reduces the conversation until it fits the budget, if it can
+    async fn apply(&self, conversation: &mut Conversation, budget: &TokenBudget) -> Result<()>;
+}
+
+/// The maximum number of tokens of the requests sent to the LLM
+pub struct TokenBudget {
+    pub max_tokens: usize,
+    /// Counter of the tokenizer of the LLM
+    pub counter: Arc<dyn TokenCounter>,
+    /// Tokens of the tool definitions sent with every request
+    pub tool_tokens: usize,
+}
+
+impl TokenBudget {
+    /// Returns the tokens of the request sending the conversation
+    pub fn tokens(&self, conversation: &Conversation) -> usize {
+        self.counter.count_messages(&conversation.messages) + self.tool_tokens
+    }
+
+    /// Returns whether the conversation fits the budget
+    pub fn fits(&self, conversation: &Conversation) -> bool {
+        self.tokens(conversation) <= self.max_tokens
+    }
+
+    fn count_messages(&self, messages: &[Message]) -> usize {
+        messages
+            .iter()
+            .map(|message| self.counter.count_message(message))
+            .sum()
+    }
+}
+
+/// Settings of the context window, read from the `[agent.context]` table
+#[derive(Debug, Deserialize, Clone, PartialEq)]
+pub struct ContextConfig {
+    /// Token budget of the conversation sent to the LLM
+    pub max_tokens: usize,
+    /// Strategies applied in order until the conversation fits the budget
+    #[serde(default = "default_strategies")]
+    pub strategies: Vec<ContextStrategyKind>,
+    /// Size tool outputs are truncated to, in tokens
+    #[serde(default = "default_max_tool_output_tokens")]
+    pub max_tool_output_tokens: usize,
+    /// Number of recent messages, tool calls with their results counting as one,
+    /// that are never summarized
+    #[serde(default = "default_keep_recent")]
+    pub keep_recent: usize,
+    /// LLM profile writing the summaries, the default one when unset
+    #[serde(default)]
+    pub summary_profile: Option<String>,
+}
+
+/// A built-in context strategy
+#[derive(Debug, Deserialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum ContextStrategyKind {
+    /// See [`TruncateToolOutputs`]
+    TruncateToolOutputs,
+    /// See [`Summarize`]
+    Summarize,
+    /// See [`DropOldest`]
+    DropOldest,
+}
+
+fn default_strategies() -> Vec<ContextStrategyKind> {
+    vec![
+        ContextStrategyKind::TruncateToolOutputs,
+        ContextStrategyKind::DropOldest,
+    ]
+}
+
+fn default_max_tool_output_tokens() -> usize {
+    DEFAULT_MAX_TOOL_OUTPUT_TOKENS
+}
+
+fn default_keep_recent() -> usize {
+    DEFAULT_KEEP_RECENT
+}
+
+impl ContextConfig {
+    /// Builds the configured strategies, `summarizer` writes the summaries
+    pub fn strategy(&self, summarizer: Arc<dyn LLM>) -> ContextStrategies {
+        let strategies = self
+            .strategies
+            .iter()
+            .map(|kind| -> Box<dyn ContextStrategy> {
+                match kind {
+                    ContextStrategyKind::TruncateToolOutputs => Box::new(TruncateToolOutputs {
+                        max_tool_output_tokens: self.max_tool_output_tokens,
+                    }),
+                    ContextStrategyKind::Summarize => Box::new(Summarize {
+                        llm: summarizer.clone(),
+                        keep_recent: self.keep_recent,
+                    }),
+                    ContextStrategyKind::DropOldest => Box::new(DropOldest),
+                }
+            })
+            .collect();
+        ContextStrategies(strategies)
+    }
+}
+
+/// Applies strategies in order, stopping once the conversation fits the budget
+pub struct ContextStrategies(pub Vec<Box<dyn ContextStrategy>>);
+
+#[async_trait]
+impl ContextStrategy for ContextStrategies {
+    async fn apply(&self, conversation: &mut Conversation, budget: &TokenBudget) -> Result<()> {
+        for strategy in &self.0 {
+            if budget.fits(conversation) {
+                break;
+            }
+            strategy.apply(conversation, budget).await?;
+        }
+        Ok(())
+    }
+}
+
+/// Truncates the largest tool outputs, oldest first, to `max_tool_output_tokens`
+pub struct TruncateToolOutputs {
+    pub max_tool_output_tokens: usize,
+}
+
+#[async_trait]
+impl ContextStrategy for TruncateToolOutputs {
+    async fn apply(&self, conversation: &mut Conversation, budget: &TokenBudget) -> Result<()> {
+        let mut tokens = budget.tokens(conversation);
+        for message in &mut conversation.messages {
+            if tokens <= budget.max_tokens {
+                break;
+            }
+            let before = budget.counter.count_message(message);
+            let Message::Tool { content, .. } = message else {
+                continue;
+            };
+            if budget.counter.count(content) <= self.max_tool_output_tokens {
+                continue;
+            }
+            let end = prefix_within(
+                content,
+                self.max_tool_output_tokens,
+                budget.counter.as_ref(),
+            );
+            let length = content.len();
+            content.truncate(end);
+            content.push_str(&format!("\n[truncated {} bytes]", length - end));
+            tokens = tokens + budget.counter.count_message(message) - before;
+        }
+        Ok(())
+    }
+}
+
+/// Drops the oldest messages, keeping the system prompt, the last user message and
+/// the last message or tool call with its results
+pub struct DropOldest;
+
+#[async_trait]
+impl ContextStrategy for DropOldest {
+    async fn apply(&self, conversation: &mut Conversation, budget: &TokenBudget) -> Result<()> {
+        let mut segments = segments(&conversation.messages);
+        let pinned = pinned_segments(&conversation.messages, &segments);
+        let mut tokens = budget.tokens(conversation);
+
+        let mut dropped = vec![false; segments.len()];
+        for index in 0..segments.len() {
+            if tokens <= budget.max_tokens {
+                break;
+            }
+            if pinned[index] {
+                continue;
+            }
+            tokens -= budget.count_messages(&conversation.messages[segments[index].clone()]);
+            dropped[index] = true;
+        }
+        // The first message after the system prompt must be a user message
+        for index in 0..segments.len() {
+            let first = &conversation.messages[segments[index].start];
+            if dropped[index] || matches!(first, Message::System { .. }) {
+                continue;
+            }
+            if matches!(first, Message::User { .. }) || pinned[index] {
+                break;
+            }
+            dropped[index] = true;
+        }
+
+        let mut dropped = dropped.into_iter();
+        segments.retain(|_| !dropped.next().unwrap_or_default());
+        conversation.messages = segments
+            .into_iter()
+            .flat_map(|range| conversation.messages[range].to_vec())
+            .collect();
+        Ok(())
+    }
+}
+
+/// Replaces the older messages, including previous summaries, with a summary
+/// written by the LLM
+///
+/// The system prompt, the last user message and the `keep_recent` most recent
+/// messages are kept as is, except the recent messages preceding the first kept
+/// user message.
+pub struct Summarize {
+    pub llm: Arc<dyn LLM>,
+    pub keep_recent: usize,
+}
+
+#[async_trait]
+impl ContextStrategy for Summarize {
+    async fn apply(&self, conversation: &mut Conversation, budget: &TokenBudget) -> Result<()> {
+        if budget.fits(conversation) {
+            return Ok(());
+        }
+        let segments = segments(&conversation.messages);
+        let pinned = pinned_segments(&conversation.messages, &segments);
+        let recent = segments.len().saturating_sub(self.keep_recent);
+        let mut summarized: Vec<usize> = (0..recent)
+            .filter(|&index| {
+                !pinned[index] || is_summary(&conversation.messages[segments[index].start])
+            })
+            .collect();
+        if summarized.is_empty() {
+            return Ok(());
+        }
+        // The first message after the system prompt and the summary must be a user
+        // message, the recent messages before it are summarized as well
+        for index in 0..segments.len() {
+            let first = &conversation.messages[segments[index].start];
+            if summarized.contains(&index) || matches!(first, Message::System { .. }) {
+                continue;
+            }
+            if matches!(first, Message::User { .. }) || pinned[index] {
+                break;
+            }
+            summarized.push(index);
+        }
+        summarized.sort_unstable();
+
+        let transcript: Vec<String> = summarized
+            .iter()
+            .flat_map(|&index| conversation.messages[segments[index].clone()].iter())
+            .map(render_message)
+            .collect();
+        let mut request = Conversation::new_with_prompt(
+            SUMMARY_MAX_TOKENS,
+            "Summarize the following conversation between a user and an assistant using \
+             tools. Keep the facts, tool results and decisions needed to continue it. \
+             Reply with the summary only."
+                .to_string(),
+        )
+        .with_params(GenerationParams {
+            tool_choice: Some(ToolChoice::None),
+            ..Default::default()
+        });
+        request.append_user_content(transcript.join("\n\n"));
+        let summary = self.llm.send(request).await?.content.unwrap_or_default();
+
+        let mut messages = Vec::with_capacity(conversation.messages.len());
+        for (index, range) in segments.into_iter().enumerate() {
+            if index == summarized[0] {
+                messages.push(Message::System {
+                    content: format!("{}\n{}", SUMMARY_PREFIX, summary),
+                });
+            }
+            if !summarized.contains(&index) {
+                messages.extend_from_slice(&conversation.messages[range]);
+            }
+        }
+        conversation.messages = messages;
+        Ok(())
+    }
+}
+
+/// Splits the messages into the units that are dropped or summarized together: a
+/// message, or an assistant message with its tool calls followed by their results
+fn segments(messages: &[Message]) -> Vec<Range<usize>> {
+    let mut segments: Vec<Range<usize>> = Vec::new();
+    for (index, message) in messages.iter().enumerate() {
+        let extends_tool_calls = matches!(message, Message::Tool { .. })
+            && segments.last().is_some_and(|last| {
+                matches!(&messages[last.start], Message::Assistant { tool_calls, .. } if !tool_calls.is_empty())
+            });
+        match segments.last_mut() {
+            Some(last) if extends_tool_calls => last.end = index + 1,
+            _ => segments.push(index..index + 1),
+        }
+    }
+    segments
+}
+
+/// Returns which segments are kept: system messages, the last user message and
+/// the last segment
+fn pinned_segments(messages: &[Message], segments: &[Range<usize>]) -> Vec<bool> {
+    let last_user = segments
+        .iter()
+        .rposition(|range| matches!(messages[range.start], Message::User { .. }));
+    segments
+        .iter()
+        .enumerate()
+        .map(|(index, range)| {
+            matches!(messages[range.start], Message::System { .. })
+                || Some(index) == last_user
+                || index + 1 == segments.len()
+        })
+        .collect()
+}
+
+/// Returns the length of the longest prefix of the text counting at most `max_tokens`
+fn prefix_within(text: &str, max_tokens: usize, counter: &dyn TokenCounter) -> usize {
+    let ends: Vec<usize> = text.char_indices().map(|(index, _)| index).collect();
+    let kept = ends.partition_point(|&end| counter.count(&text[..end]) <= max_tokens);
+    ends[kept.saturating_sub(1)]
+}
+
+fn is_summary(message: &Message) -> bool {
+    matches!(message, Message::System { content } if content.starts_with(SUMMARY_PREFIX))
+}
+
+fn render_message(message: &Message) -> String {
+    match message {
+        Message::System { content } => format!("system: {}", content),
+        Message::User { content } => format!("user: {}", content),
+        Message::Assistant {
+            content,
+            tool_calls,
+        } => {
+            let mut text = format!("assistant: {}", content.as_deref().unwrap_or_default());
+            for call in tool_calls {
+                text.push_str(&format!("\ncalled {}({})", call.name, call.arguments));
+            }
+            text
+        }
+        Message::Tool {
+            tool_call_id,
+            content,
+        } => format!("result of {}: {}", tool_call_id, content),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::agent::testing::ScriptedLlm;
+    use crate::llm::message::ToolCall;
+    use crate::llm::tokens::HeuristicTokenCounter;
+
+    fn budget(max_tokens: usize) -> TokenBudget {
+        TokenBudget {
+            max_tokens,
+            counter: Arc::new(HeuristicTokenCounter::default()),
+            tool_tokens: 0,
+        }
+    }
+
+    fn conversation() -> Conversation {
+        let mut conversation = Conversation::new_with_prompt(256, "Be brief".to_string());
+        conversation.append_user_content("first question".to_string());
+        conversation.append_assistant_content("first answer".to_string());
+        conversation.append_user_content("second question".to_string());
+        conversation.append_tool_call_response(&[ToolCall {
+            id: "call_0".to_string(),
+            name: "fetch".to_string(),
+            arguments: "{}".to_string(),
+        }]);
+        conversation.append_tool_call_content("x".repeat(4_000), "call_0".to_string());
+        conversation.append_assistant_content("second answer".to_string());
+        conversation
+    }
+
+    #[tokio::test]
+    async fn drops_oldest_messages_and_keeps_tool_results_with_their_calls() {
+        let mut conversation = conversation();
+        DropOldest
+            .apply(&mut conversation, &budget(100))
+            .await
+            .unwrap();
+
+        let roles: Vec<_> = conversation
+            .messages
+            .iter()
+            .map(|message| match message {
+                Message::System { .. } => "system",
+                Message::User { .. } => "user",
+                Message::Assistant { .. } => "assistant",
+                Message::Tool { .. } => "tool",
+            })
+            .collect();
+        assert_eq!(roles, ["system", "user", "assistant"]);
+        assert_eq!(
+            conversation.messages[1],
+            Message::User {
+                content: "second question".to_string()
+            }
+        );
+    }
+
+    #[tokio::test]
+    async fn summarizes_up_to_a_user_message() {
+        let mut conversation = Conversation::new_with_prompt(256, "Be brief".to_string());
+        conversation.append_user_content("first question".repeat(100));
+        conversation.append_assistant_content("first answer".repeat(100));
+        conversation.append_user_content("second question".to_string());
+        conversation.append_tool_call_response(&[ToolCall {
+            id: "call_0".to_string(),
+            name: "fetch".to_string(),
+            arguments: "{}".to_string(),
+        }]);
+        conversation.append_tool_call_content("fetched".to_string(), "call_0".to_string());
+        conversation.append_user_content("third question".to_string());
+        let strategy = Summarize {
+            llm: Arc::new(ScriptedLlm::answering("the user asked twice")),
+            keep_recent: 2,
+        };
+        strategy
+            .apply(&mut conversation, &budget(100))
+            .await
+            .unwrap();
+
+        assert_eq!(
+            conversation.messages,
+            [
+                Message::System {
+                    content: "Be brief".to_string()
+                },
+                Message::System {
+                    content: format!("{}\nthe user asked twice", SUMMARY_PREFIX)
+                },
+                Message::User {
+                    content: "third question".to_string()
+                },
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn truncates_tool_outputs_within_budget() {
+        let mut conversation = conversation();
+        let strategy = TruncateToolOutputs {
+            max_tool_output_tokens: 100,
+        };
+        strategy
+            .apply(&mut conversation, &budget(300))
+            .await
+            .unwrap();
+
+        assert_eq!(conversation.messages.len(), 7);
+        let Message::Tool { content, .. } = &conversation.messages[5] else {
+            panic!("expected a tool message");
+        };
+        assert!(content.starts_with(&"x".repeat(400)));
+        assert!(content.ends_with("[truncated 3600 bytes]"));
+        assert!(budget(300).fits(&conversation));
+    }
+
+    #[tokio::test]
+    async fn truncates_dense_tool_outputs_by_tokens() {
+        let mut conversation = conversation();
+        conversation.messages[5] = Message::Tool {
+            content: "界".repeat(1_000),
+            tool_call_id: "call_0".to_string(),
+        };
+        let strategy = TruncateToolOutputs {
+            max_tool_output_tokens: 100,
+        };
+        strategy
+            .apply(&mut conversation, &budget(300))
+            .await
+            .unwrap();
+
+        let Message::Tool { content, .. } = &conversation.messages[5] else {
+            panic!("expected a tool message");
+        };
+        assert!(content.starts_with(&"界".repeat(133)));
+        assert!(content.ends_with("[truncated 2601 bytes]"));
+        assert!(budget(300).fits(&conversation));
+    }
+}
@@ -0,0 +1,39 @@
+use super::policy::LoopLimit;
+use super::report::{LlmAttempt, LlmCall};
+use crate::llm::message::Conversation;
+
+/// Events emitted while the agent runs a conversation
+#[derive(Debug, Clone)]
+pub enum AgentEvent {
+    /// A new LLM turn started, turns are numbered from 1
+    TurnStarted { turn: usize },
+    /// A request was sent to the LLM, failed attempts are retried or fall back to
+    /// the next profile of the chain
+    LlmAttempt(LlmAttempt),
+    /// Tokens consumed by the LLM response of the current turn
+    Usage(LlmCall),
+    /// A piece of the assistant text of the current turn
+    TextDelta(String),
+    /// A tool call requested by the LLM is being executed
+    ToolCallStarted {
+        id: String,
+        name: String,
+        arguments: String,
+    },
+    /// A tool call finished, failed calls carry the error message sent to the LLM
+    ToolResult {
+        id: String,
+        name: String,
+        content: String,
+        is_error: bool,
+    },
+    /// An LLM turn and its tool calls finished
+    TurnFinished { turn: usize },
+    /// The LLM produced its final answer, this is the last event of the run
+    FinalAnswer(String),
+    /// The run was stopped by a loop limit, this is the last event of the run
+    LimitReached {
+        limit: LoopLimit,
+        transcript: Box<Conversation>,
+    },
+}
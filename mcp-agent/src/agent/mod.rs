@@ -2,3 +2,16 @@ pub mod agent;
 pub use agent::Agent;
 pub mod config;
 pub use config::Config;
+pub mod context;
+pub use context::{ContextStrategy, TokenBudget};
+pub mod event;
+pub use event::AgentEvent;
+pub mod policy;
+pub use policy::{AgentLoopPolicy, AgentOutcome, LoopLimit};
+mod registry;
+pub mod report;
+pub use report::{AgentRun, LlmAttempt, LlmCall, RunReport, UsageSink, with_usage_sink};
+pub mod retry;
+pub use retry::RetryPolicy;
+#[cfg(test)]
+pub(crate) mod testing;
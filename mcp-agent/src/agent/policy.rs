@@ -0,0 +1,258 @@
+use crate::llm::message::Conversation;
+use serde::Deserialize;
+use serde_json::Value;
+use std::collections::HashMap;
+use std::fmt;
+use std::future::Future;
+use std::time::{Duration, Instant};
+
+const DEFAULT_MAX_TURNS: usize = 16;
+const DEFAULT_MAX_TOOL_CALLS: usize = 64;
+const DEFAULT_MAX_REPEATED_TOOL_CALLS: usize = 3;
+
+/// Limits applied to the loop of LLM turns and tool calls of a single agent run
+///
+/// Every limit can be disabled by setting it to `None`.
+#[derive(Debug, Deserialize, Clone, PartialEq, Eq)]
+#[serde(default)]
+pub struct AgentLoopPolicy {
+    /// Maximum number of requests sent to the LLM
+    pub max_turns: Option<usize>,
+    /// Maximum number of tool calls over all turns
+    pub max_tool_calls: Option<usize>,
+    /// Wall-clock budget of the whole run, in seconds
+    pub timeout_secs: Option<u64>,
+    /// Maximum number of identical tool calls (same tool and same arguments)
+    pub max_repeated_tool_calls: Option<usize>,
+}
+
+impl Default for AgentLoopPolicy {
+    fn default() -> Self {
+        Self {
+            max_turns: Some(DEFAULT_MAX_TURNS),
+            max_tool_calls: Some(DEFAULT_MAX_TOOL_CALLS),
+            timeout_secs: None,
+            max_repeated_tool_calls: Some(DEFAULT_MAX_REPEATED_TOOL_CALLS),
+        }
+    }
+}
+
+impl AgentLoopPolicy {
+    /// Creates a policy without any limit
+    pub fn unbounded() -> Self {
+        Self {
+            max_turns: None,
+            max_tool_calls: None,
+            timeout_secs: None,
+            max_repeated_tool_calls: None,
+        }
+    }
+}
+
+/// The limit of an [`AgentLoopPolicy`] that stopped an agent run
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum LoopLimit {
+    /// The maximum number of LLM turns was reached
+    MaxTurns(usize),
+    /// The maximum number of tool calls was reached
+    MaxToolCalls(usize),
+    /// The wall-clock budget was exhausted
+    Timeout(Duration),
+    /// The same tool was called with the same arguments too many times
+    RepeatedToolCall {
+        name: String,
+        arguments: String,
+        count: usize,
+    },
+}
+
+impl fmt::Display for LoopLimit {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            LoopLimit::MaxTurns(max) => write!(f, "reached the maximum of {} turns", max),
+            LoopLimit::MaxToolCalls(max) => {
+                write!(f, "reached the maximum of {} tool calls", max)
+            }
+            LoopLimit::Timeout(budget) => {
+                write!(f, "exceeded the time budget of {}s", budget.as_secs())
+            }
+            LoopLimit::RepeatedToolCall {
+                name,
+                arguments,
+                count,
+            } => write!(
+                f,
+                "tool [{}] was called {} times with the same arguments: {}",
+                name, count, arguments
+            ),
+        }
+    }
+}
+
+/// The result of an agent run
+#[derive(Debug, Clone)]
+pub enum AgentOutcome {
+    /// The LLM produced a final answer
+    Completed(String),
+    /// The run was stopped by a loop limit
+    LimitReached {
+        /// The limit that was reached
+        limit: LoopLimit,
+        /// The conversation as it was when the run stopped
+        transcript: Box<Conversation>,
+    },
+}
+
+/// Tracks the progress of an agent run against its policy
+pub(crate) struct LoopGuard<'a> {
+    policy: &'a AgentLoopPolicy,
+    started_at: Instant,
+    turns: usize,
+    tool_calls: usize,
+    repeated: HashMap<(String, String), usize>,
+}
+
+impl<'a> LoopGuard<'a> {
+    /// Starts tracking a new run
+    pub fn new(policy: &'a AgentLoopPolicy) -> Self {
+        Self {
+            policy,
+            started_at: Instant::now(),
+            turns: 0,
+            tool_calls: 0,
+            repeated: HashMap::new(),
+        }
+    }
+
+    /// Accounts for a new LLM turn, returns the limit that forbids it if any
+    pub fn next_turn(&mut self) -> Option<LoopLimit> {
+        if let Some(limit) = self.check_timeout() {
+            return Some(limit);
+        }
+        if let Some(max) = self.policy.max_turns.filter(|&max| self.turns >= max) {
+            return Some(LoopLimit::MaxTurns(max));
+        }
+        self.turns += 1;
+        None
+    }
+
+    /// Returns the number of LLM turns started so far
+    pub fn turns(&self) -> usize {
+        self.turns
+    }
+
+    /// Accounts for a tool call, returns the limit that forbids it if any
+    pub fn record_tool_call(&mut self, name: &str, arguments: &str) -> Option<LoopLimit> {
+        self.tool_calls += 1;
+        if let Some(max) = self
+            .policy
+            .max_tool_calls
+            .filter(|&max| self.tool_calls > max)
+        {
+            return Some(LoopLimit::MaxToolCalls(max));
+        }
+
+        let arguments = normalize_arguments(arguments);
+        let count = self
+            .repeated
+            .entry((name.to_string(), arguments.clone()))
+            .or_default();
+        *count += 1;
+        let count = *count;
+        if self
+            .policy
+            .max_repeated_tool_calls
+            .is_some_and(|max| count > max)
+        {
+            return Some(LoopLimit::RepeatedToolCall {
+                name: name.to_string(),
+                arguments,
+                count,
+            });
+        }
+        None
+    }
+
+    /// Returns the timeout limit if the time budget is exhausted
+    pub fn check_timeout(&self) -> Option<LoopLimit> {
+        let budget = self.budget()?;
+        if self.started_at.elapsed() >= budget {
+            return Some(LoopLimit::Timeout(budget));
+        }
+        None
+    }
+
+    /// Runs the future within the remaining time budget
+    pub async fn within_budget<F: Future>(&self, fut: F) -> Result<F::Output, LoopLimit> {
+        match self.budget() {
+            Some(budget) => {
+                let remaining = budget.saturating_sub(self.started_at.elapsed());
+                tokio::time::timeout(remaining, fut)
+                    .await
+                    .map_err(|_| LoopLimit::Timeout(budget))
+            }
+            None => Ok(fut.await),
+        }
+    }
+
+    fn budget(&self) -> Option<Duration> {
+        self.policy.timeout_secs.map(Duration::from_secs)
+    }
+}
+
+/// Normalizes JSON arguments so that key order and whitespace do not matter
+fn normalize_arguments(arguments: &str) -> String {
+    serde_json::from_str::<Value>(arguments)
+        .map(|value| value.to_string())
+        .unwrap_or_else(|_| arguments.trim().to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn stops_after_max_turns() {
+        let policy = AgentLoopPolicy {
+            max_turns: Some(2),
+            ..AgentLoopPolicy::unbounded()
+        };
+        let mut guard = LoopGuard::new(&policy);
+        assert_eq!(guard.next_turn(), None);
+        assert_eq!(guard.next_turn(), None);
+        assert_eq!(guard.next_turn(), Some(LoopLimit::MaxTurns(2)));
+    }
+
+    #[test]
+    fn detects_repeated_tool_calls() {
+        let policy = AgentLoopPolicy {
+            max_repeated_tool_calls: Some(1),
+            ..AgentLoopPolicy::unbounded()
+        };
+        let mut guard = LoopGuard::new(&policy);
+        assert_eq!(guard.record_tool_call("fetch", r#"{"a":1,"b":2}"#), None);
+        assert_eq!(guard.record_tool_call("fetch", r#"{"a":2}"#), None);
+        assert_eq!(
+            guard.record_tool_call("fetch", r#"{ "b": 2, "a": 1 }"#),
+            Some(LoopLimit::RepeatedToolCall {
+                name: "fetch".to_string(),
+                arguments: r#"{"a":1,"b":2}"#.to_string(),
+                count: 2,
+            })
+        );
+    }
+
+    #[test]
+    fn stops_after_max_tool_calls() {
+        let policy = AgentLoopPolicy {
+            max_tool_calls: Some(1),
+            ..AgentLoopPolicy::unbounded()
+        };
+        let mut guard = LoopGuard::new(&policy);
+        assert_eq!(guard.record_tool_call("search", "{}"), None);
+        assert_eq!(
+            guard.record_tool_call("fetch", "{}"),
+            Some(LoopLimit::MaxToolCalls(1))
+        );
+    }
+}
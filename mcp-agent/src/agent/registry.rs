@@ -0,0 +1,145 @@
+use super::agent::McpClient;
+use super::config::McpConfig;
+use rmcp::model::Tool;
+use std::collections::BTreeMap;
+use std::sync::Arc;
+use tokio::sync::Semaphore;
+
+/// A tool exposed by one of the configured MCP servers
+#[derive(Clone)]
+pub(crate) struct RegisteredTool {
+    /// Name under which the tool is exposed to the LLM
+    pub name: String,
+    /// Name of the MCP server that owns the tool
+    pub server: String,
+    /// Tool definition as reported by the MCP server
+    pub tool: Tool,
+    /// Client used to call the tool
+    pub client: McpClient,
+    /// Concurrency limit shared by all tools of the server
+    pub limiter: Option<Arc<Semaphore>>,
+}
+
+/// Registry mapping every exposed tool name to the MCP client that owns it
+///
+/// Tools are kept sorted by name so that the tool list sent to the LLM is the same
+/// from one process to the next, which keeps prompts cacheable.
+#[derive(Default)]
+pub(crate) struct ToolRegistry {
+    tools: BTreeMap<String, RegisteredTool>,
+}
+
+impl ToolRegistry {
+    /// Registers all tools reported by an MCP server, named by its naming strategy
+    ///
+    /// When an exposed name is already taken the first registered tool wins.
+    pub fn register(
+        &mut self,
+        server: &str,
+        config: &McpConfig,
+        client: &McpClient,
+        tools: Vec<Tool>,
+    ) {
+        let limiter = config
+            .max_concurrency
+            .map(|permits| Arc::new(Semaphore::new(permits.max(1))));
+        for tool in tools {
+            let name = config.exposed_tool_name(server, &tool.name);
+            if let Some(exists) = self.tools.get(&name) {
+                println!(
+                    "warning: tool [{}] of mcp [{}] is already provided by mcp [{}], skipped",
+                    name, server, exists.server
+                );
+                continue;
+            }
+
+            self.tools.insert(
+                name.clone(),
+                RegisteredTool {
+                    name,
+                    server: server.to_string(),
+                    tool,
+                    client: client.clone(),
+                    limiter: limiter.clone(),
+                },
+            );
+        }
+    }
+
+    /// Returns the registered tool with the given exposed name
+    pub fn get(&self, name: &str) -> Option<&RegisteredTool> {
+        self.tools.get(name)
+    }
+
+    /// Returns an iterator over all registered tools, sorted by exposed name
+    pub fn tools(&self) -> impl Iterator<Item = &RegisteredTool> {
+        self.tools.values()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::agent::Config;
+    use crate::agent::testing::EchoServer;
+    use rmcp::model::CallToolRequestParam;
+
+    #[tokio::test]
+    async fn names_tools_by_strategy_and_keeps_the_first_of_collisions() {
+        let config: Config = toml::from_str(
+            r#"
+            [mcp_servers.fetch]
+            command = "uvx"
+            tool_naming = "alias"
+            [mcp_servers.fetch.tool_aliases]
+            fetch = "fetch_page"
+
+            [mcp_servers.search]
+            command = "uvx"
+
+            [mcp_servers.web]
+            command = "uvx"
+            tool_naming = "prefix"
+            tool_prefix = "w"
+            "#,
+        )
+        .unwrap();
+        let server = EchoServer::new(&["fetch", "search"]);
+        let client = server.connect().await;
+        let tools = client.list_tools().await.unwrap().tools;
+
+        let mut registry = ToolRegistry::default();
+        for name in ["fetch", "search", "web"] {
+            registry.register(name, &config.mcp_servers[name], &client, tools.clone());
+        }
+
+        let names: Vec<_> = registry
+            .tools()
+            .map(|registered| (registered.name.as_str(), registered.server.as_str()))
+            .collect();
+        assert_eq!(
+            names,
+            [
+                ("fetch", "search"),
+                ("fetch_page", "fetch"),
+                ("search", "fetch"),
+                ("w__fetch", "web"),
+                ("w__search", "web"),
+            ]
+        );
+        assert_eq!(registry.get("fetch_page").unwrap().tool.name, "fetch");
+        assert_eq!(registry.get("w__search").unwrap().tool.name, "search");
+        assert!(registry.get("search__search").is_none());
+
+        let registered = registry.get("w__search").unwrap();
+        registered
+            .client
+            .call_tool(CallToolRequestParam {
+                name: registered.tool.name.clone(),
+                arguments: None,
+            })
+            .await
+            .unwrap();
+        assert_eq!(server.calls(), ["search"]);
+    }
+}
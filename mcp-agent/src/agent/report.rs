@@ -0,0 +1,80 @@
+use super::policy::AgentOutcome;
+use crate::llm::message::Usage;
+use std::sync::Arc;
+use std::time::Duration;
+
+/// Receives the usage of the LLM responses, see [`with_usage_sink`]
+pub type UsageSink = Arc<dyn Fn(&LlmCall) + Send + Sync>;
+
+tokio::task_local! {
+    static USAGE_SINK: UsageSink;
+}
+
+/// Runs the future, passing the usage of every LLM response the agent gets while it
+/// runs to the sink, e.g. to charge the tokens to the client of an MCP request
+pub async fn with_usage_sink<F: Future>(sink: UsageSink, future: F) -> F::Output {
+    USAGE_SINK.scope(sink, future).await
+}
+
+/// Passes the usage to the sink of the current task, if any
+pub(crate) fn record_usage(call: &LlmCall) {
+    let _ = USAGE_SINK.try_with(|sink| sink(call));
+}
+
+/// A request sent to the LLM during an agent run
+#[derive(Debug, Clone, PartialEq)]
+pub struct LlmAttempt {
+    /// Profile of the LLM the request was sent to
+    pub profile: String,
+    /// Attempt number on this profile, numbered from 1
+    pub attempt: u32,
+    pub duration: Duration,
+    /// Error of a failed attempt
+    pub error: Option<String>,
+}
+
+/// Tokens consumed by a successful LLM request
+#[derive(Debug, Clone, PartialEq)]
+pub struct LlmCall {
+    /// Profile of the LLM that answered
+    pub profile: String,
+    pub model: String,
+    pub usage: Usage,
+    /// Cost of the request, `None` when the model has no `[pricing]` entry
+    pub cost: Option<f64>,
+}
+
+/// What happened during an agent run
+#[derive(Debug, Clone, Default)]
+pub struct RunReport {
+    /// Every LLM request, including the retried and failed ones
+    pub attempts: Vec<LlmAttempt>,
+    /// Token usage of every LLM request that reported it
+    pub calls: Vec<LlmCall>,
+}
+
+impl RunReport {
+    /// Returns the tokens consumed by all the LLM requests of the run
+    pub fn total_usage(&self) -> Usage {
+        let mut total = Usage::default();
+        for call in &self.calls {
+            total += call.usage;
+        }
+        total
+    }
+
+    /// Returns the cost of the priced LLM requests of the run, `None` if none is priced
+    pub fn total_cost(&self) -> Option<f64> {
+        self.calls
+            .iter()
+            .filter_map(|call| call.cost)
+            .reduce(|total, cost| total + cost)
+    }
+}
+
+/// The result of an agent run with its report
+#[derive(Debug, Clone)]
+pub struct AgentRun {
+    pub outcome: AgentOutcome,
+    pub report: RunReport,
+}
@@ -0,0 +1,148 @@
+use crate::error::Error;
+use rand::Rng;
+use serde::Deserialize;
+use std::time::Duration;
+
+const DEFAULT_MAX_RETRIES: u32 = 2;
+const DEFAULT_INITIAL_BACKOFF_MS: u64 = 500;
+const DEFAULT_MAX_BACKOFF_MS: u64 = 10_000;
+const DEFAULT_BACKOFF_MULTIPLIER: f64 = 2.0;
+
+/// Retries of the LLM requests of a profile, read from the `[llm.retry]` table
+///
+/// Only transient errors are retried: rate limits, server errors, timeouts and
+/// connection failures.
+#[derive(Debug, Deserialize, Clone, PartialEq)]
+#[serde(default)]
+pub struct RetryPolicy {
+    /// Number of retries after the first attempt
+    pub max_retries: u32,
+    /// Delay before the first retry, in milliseconds
+    pub initial_backoff_ms: u64,
+    /// Maximum delay between two attempts, in milliseconds
+    pub max_backoff_ms: u64,
+    /// Factor applied to the delay after each retry
+    pub multiplier: f64,
+    /// Whether delays are randomized between half and all of their value
+    pub jitter: bool,
+    /// Timeout of a single request, in seconds
+    pub request_timeout_secs: Option<u64>,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        Self {
+            max_retries: DEFAULT_MAX_RETRIES,
+            initial_backoff_ms: DEFAULT_INITIAL_BACKOFF_MS,
+            max_backoff_ms: DEFAULT_MAX_BACKOFF_MS,
+            multiplier: DEFAULT_BACKOFF_MULTIPLIER,
+            jitter: true,
+            request_timeout_secs: None,
+        }
+    }
+}
+
+impl RetryPolicy {
+    /// Returns the delay before the given retry, numbered from 1, or `None` when
+    /// the error must not be retried
+    ///
+    /// A `Retry-After` sent by the Anthropic API is honored, unless it exceeds
+    /// `max_backoff_ms` in which case the next profile of the fallback chain is tried.
+    /// The OpenAI client does not expose the response headers, its errors back off
+    /// exponentially.
+    pub fn backoff(&self, retry: u32, err: &Error) -> Option<Duration> {
+        let Error::LlmUnavailable { retry_after, .. } = err else {
+            return None;
+        };
+        if retry > self.max_retries {
+            return None;
+        }
+
+        let max = Duration::from_millis(self.max_backoff_ms);
+        if let Some(retry_after) = retry_after {
+            return (*retry_after <= max).then_some(*retry_after);
+        }
+
+        let exponent = retry.saturating_sub(1).min(i32::MAX as u32) as i32;
+        // Clamped before building the duration, which panics on overflow
+        let secs = (self.initial_backoff_ms as f64 / 1000.0
+            * self.multiplier.max(1.0).powi(exponent))
+        .min(max.as_secs_f64());
+        let delay = Duration::try_from_secs_f64(secs).unwrap_or(max);
+        if self.jitter {
+            Some(delay.mul_f64(rand::rng().random_range(0.5..=1.0)))
+        } else {
+            Some(delay)
+        }
+    }
+
+    /// Returns the timeout of a single request, if any
+    pub fn request_timeout(&self) -> Option<Duration> {
+        self.request_timeout_secs.map(Duration::from_secs)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn unavailable(retry_after: Option<Duration>) -> Error {
+        Error::LlmUnavailable {
+            message: "503 Service Unavailable".to_string(),
+            retry_after,
+        }
+    }
+
+    #[test]
+    fn backs_off_exponentially_up_to_the_limit() {
+        let policy = RetryPolicy {
+            max_retries: 4,
+            initial_backoff_ms: 100,
+            max_backoff_ms: 250,
+            jitter: false,
+            ..RetryPolicy::default()
+        };
+        let err = unavailable(None);
+
+        assert_eq!(policy.backoff(1, &err), Some(Duration::from_millis(100)));
+        assert_eq!(policy.backoff(2, &err), Some(Duration::from_millis(200)));
+        assert_eq!(policy.backoff(3, &err), Some(Duration::from_millis(250)));
+        assert_eq!(policy.backoff(5, &err), None);
+        assert_eq!(
+            policy.backoff(1, &Error::Llm("bad request".to_string())),
+            None
+        );
+    }
+
+    #[test]
+    fn caps_the_backoff_of_late_retries() {
+        let policy = RetryPolicy {
+            max_retries: u32::MAX,
+            multiplier: 10.0,
+            jitter: false,
+            ..RetryPolicy::default()
+        };
+        let max = Duration::from_millis(policy.max_backoff_ms);
+        let err = unavailable(None);
+
+        assert_eq!(policy.backoff(25, &err), Some(max));
+        assert_eq!(policy.backoff(u32::MAX, &err), Some(max));
+    }
+
+    #[test]
+    fn honors_retry_after() {
+        let policy = RetryPolicy {
+            max_backoff_ms: 5_000,
+            ..RetryPolicy::default()
+        };
+
+        assert_eq!(
+            policy.backoff(1, &unavailable(Some(Duration::from_secs(2)))),
+            Some(Duration::from_secs(2))
+        );
+        assert_eq!(
+            policy.backoff(1, &unavailable(Some(Duration::from_secs(60)))),
+            None
+        );
+    }
+}
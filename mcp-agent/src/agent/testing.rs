@@ -0,0 +1,157 @@
+use super::agent::McpClient;
+use crate::error::Result;
+use crate::llm::llm::LLM;
+use crate::llm::message::{ChatResponse, Conversation, ToolCall};
+use async_trait::async_trait;
+use rmcp::model::{
+    CallToolRequestParam, CallToolResult, Content, ListToolsResult, PaginatedRequestParam,
+    ServerCapabilities, ServerInfo, Tool,
+};
+use rmcp::service::{RequestContext, ServiceExt};
+use rmcp::{Error as McpError, RoleServer, ServerHandler};
+use serde_json::Map;
+use std::collections::VecDeque;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+/// Configuration of an agent whose `echo` MCP server is the test server
+pub(crate) const ECHO_CONFIG: &str = "[mcp_servers.echo]\ncommand = \"echo\"\n";
+
+/// Creates a call of an [`EchoServer`] tool answering after `delay_ms`
+pub(crate) fn tool_call(id: &str, name: &str, delay_ms: u64) -> ToolCall {
+    ToolCall {
+        id: id.to_string(),
+        name: name.to_string(),
+        arguments: format!(r#"{{"delay_ms": {}}}"#, delay_ms),
+    }
+}
+
+/// LLM requesting the tool calls of each turn in order, then answering
+pub(crate) struct ScriptedLlm {
+    turns: Mutex<VecDeque<Vec<ToolCall>>>,
+    answer: String,
+    text: Option<String>,
+}
+
+impl ScriptedLlm {
+    /// Creates an LLM requesting the tool calls of the turns before answering
+    pub fn new(turns: Vec<Vec<ToolCall>>, answer: &str) -> Self {
+        Self {
+            turns: Mutex::new(turns.into()),
+            answer: answer.to_string(),
+            text: None,
+        }
+    }
+
+    /// Sends the text along with the tool calls of every turn
+    pub fn with_text(mut self, text: &str) -> Self {
+        self.text = Some(text.to_string());
+        self
+    }
+
+    /// Creates an LLM answering right away
+    pub fn answering(answer: &str) -> Self {
+        Self::new(Vec::new(), answer)
+    }
+}
+
+#[async_trait]
+impl LLM for ScriptedLlm {
+    async fn send(&self, conversation: Conversation) -> Result<ChatResponse> {
+        let mut response = ChatResponse::new(conversation);
+        match self.turns.lock().unwrap().pop_front() {
+            Some(calls) => {
+                response.content = self.text.clone();
+                response.tool_calls = Some(calls);
+            }
+            None => response.content = Some(self.answer.clone()),
+        }
+        Ok(response)
+    }
+}
+
+/// In-process MCP server whose tools echo the tool name, after sleeping `delay_ms`
+#[derive(Clone, Default)]
+pub(crate) struct EchoServer {
+    tools: Vec<String>,
+    calls: Arc<Mutex<Vec<String>>>,
+    running: Arc<AtomicUsize>,
+    max_running: Arc<AtomicUsize>,
+}
+
+impl EchoServer {
+    /// Creates a server providing the named tools
+    pub fn new(tools: &[&str]) -> Self {
+        Self {
+            tools: tools.iter().map(|tool| tool.to_string()).collect(),
+            ..Default::default()
+        }
+    }
+
+    /// Connects a client to the server over an in-memory pipe
+    pub async fn connect(&self) -> McpClient {
+        let (client_io, server_io) = tokio::io::duplex(4096);
+        let server = self.clone();
+        tokio::spawn(async move {
+            if let Ok(service) = server.serve(server_io).await {
+                let _ = service.waiting().await;
+            }
+        });
+        McpClient::StdioClient(Arc::new(().serve(client_io).await.unwrap()))
+    }
+
+    /// Returns the names of the called tools, in the order the calls started
+    pub fn calls(&self) -> Vec<String> {
+        self.calls.lock().unwrap().clone()
+    }
+
+    /// Returns the highest number of calls that were running at the same time
+    pub fn max_running(&self) -> usize {
+        self.max_running.load(Ordering::SeqCst)
+    }
+}
+
+impl ServerHandler for EchoServer {
+    async fn list_tools(
+        &self,
+        _request: Option<PaginatedRequestParam>,
+        _context: RequestContext<RoleServer>,
+    ) -> Result<ListToolsResult, McpError> {
+        let tools = self
+            .tools
+            .iter()
+            .map(|name| Tool::new(name.clone(), format!("echoes {}", name), Map::new()))
+            .collect();
+        Ok(ListToolsResult::with_all_items(tools))
+    }
+
+    async fn call_tool(
+        &self,
+        request: CallToolRequestParam,
+        _context: RequestContext<RoleServer>,
+    ) -> Result<CallToolResult, McpError> {
+        self.calls.lock().unwrap().push(request.name.to_string());
+        let running = self.running.fetch_add(1, Ordering::SeqCst) + 1;
+        self.max_running.fetch_max(running, Ordering::SeqCst);
+
+        let delay = request
+            .arguments
+            .as_ref()
+            .and_then(|arguments| arguments.get("delay_ms")?.as_u64())
+            .unwrap_or_default();
+        tokio::time::sleep(Duration::from_millis(delay)).await;
+        self.running.fetch_sub(1, Ordering::SeqCst);
+
+        Ok(CallToolResult::success(vec![Content::text(
+            request.name.to_string(),
+        )]))
+    }
+
+    fn get_info(&self) -> ServerInfo {
+        ServerInfo {
+            capabilities: ServerCapabilities::builder().enable_tools().build(),
+            ..Default::default()
+        }
+    }
+}
@@ -0,0 +1,211 @@
+use crate::agent::policy::LoopLimit;
+use async_openai::error::OpenAIError;
+use std::fmt::Display;
+use std::time::Duration;
+
+/// Errors returned by the mcp-agent crate
+#[derive(Debug, thiserror::Error)]
+pub enum Error {
+    /// The configuration is missing, unreadable or invalid
+    #[error("config error: {0}")]
+    Config(String),
+
+    /// An MCP server could not be started or connected to
+    #[error("mcp [{server}] transport error: {message}")]
+    Transport { server: String, message: String },
+
+    /// An MCP request failed at the protocol level
+    #[error("mcp protocol error: {0}")]
+    Mcp(#[from] rmcp::ServiceError),
+
+    /// The LLM API returned an error
+    #[error("llm api error: {0}")]
+    Llm(String),
+
+    /// The LLM API is temporarily unavailable: rate limited, server error, timeout
+    /// or connection failure
+    #[error("llm api unavailable: {message}")]
+    LlmUnavailable {
+        message: String,
+        /// Delay requested by the `Retry-After` header of the response, if any and
+        /// readable, i.e. with the Anthropic provider
+        retry_after: Option<Duration>,
+    },
+
+    /// The LLM answer does not match the requested format
+    #[error("invalid llm response: {0}")]
+    InvalidResponse(String),
+
+    /// A tool call could not be executed
+    #[error("tool [{tool}] error: {message}")]
+    Tool { tool: String, message: String },
+
+    /// The agent loop was stopped by a limit of its policy
+    #[error("agent loop stopped: {0}")]
+    LoopLimit(LoopLimit),
+
+    /// A prompt template is not defined or misses variables
+    #[error("prompt template error: {0}")]
+    Template(String),
+
+    /// A conversation edit would break the pairing of tool calls and results
+    #[error("invalid conversation edit: {0}")]
+    Conversation(String),
+
+    /// A session could not be loaded from or saved to its store
+    #[error("session store error: {0}")]
+    Session(String),
+
+    /// An I/O error of the MCP server listeners
+    #[error("io error: {0}")]
+    Io(#[from] std::io::Error),
+}
+
+/// Result type of the mcp-agent crate
+pub type Result<T, E = Error> = std::result::Result<T, E>;
+
+impl Error {
+    /// Creates a transport error for the given MCP server
+    pub fn transport(server: &str, err: impl Display) -> Self {
+        Error::Transport {
+            server: server.to_string(),
+            message: err.to_string(),
+        }
+    }
+
+    /// Creates an error for a transient failure of the LLM API
+    pub fn llm_unavailable(err: impl Display, retry_after: Option<Duration>) -> Self {
+        Error::LlmUnavailable {
+            message: err.to_string(),
+            retry_after,
+        }
+    }
+
+    /// Creates a session store error
+    pub fn session(err: impl Display) -> Self {
+        Error::Session(err.to_string())
+    }
+
+    /// Creates a tool execution error for the given tool
+    pub fn tool(tool: &str, err: impl Display) -> Self {
+        Error::Tool {
+            tool: tool.to_string(),
+            message: err.to_string(),
+        }
+    }
+}
+
+impl From<OpenAIError> for Error {
+    /// Classifies the error, async-openai does not expose the HTTP status: server
+    /// errors are reported without type nor code, rate limits with a `rate_limit` code
+    ///
+    /// Nor does it expose the headers, so the `Retry-After` of the response is unknown.
+    fn from(err: OpenAIError) -> Self {
+        let transient = match &err {
+            OpenAIError::Reqwest(err) => is_transient(err),
+            OpenAIError::ApiError(api) => {
+                let rate_limited = [&api.r#type, &api.code]
+                    .into_iter()
+                    .flatten()
+                    .any(|value| value.contains("rate_limit"));
+                rate_limited || (api.r#type.is_none() && api.code.is_none())
+            }
+            OpenAIError::StreamError(message) => {
+                !message.contains("Invalid status code: 4")
+                    || message.contains("Invalid status code: 429")
+            }
+            _ => false,
+        };
+        if transient {
+            Error::llm_unavailable(err, None)
+        } else {
+            Error::Llm(err.to_string())
+        }
+    }
+}
+
+impl From<reqwest::Error> for Error {
+    fn from(err: reqwest::Error) -> Self {
+        if is_transient(&err) {
+            Error::llm_unavailable(err, None)
+        } else {
+            Error::Llm(err.to_string())
+        }
+    }
+}
+
+/// Returns whether an HTTP client error is worth retrying
+fn is_transient(err: &reqwest::Error) -> bool {
+    err.is_timeout()
+        || err.is_connect()
+        || err
+            .status()
+            .is_some_and(|status| status.as_u16() == 429 || status.is_server_error())
+}
+
+impl From<LoopLimit> for Error {
+    fn from(limit: LoopLimit) -> Self {
+        Error::LoopLimit(limit)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use async_openai::error::ApiError;
+
+    fn api_error(r#type: Option<&str>, code: Option<&str>) -> Error {
+        OpenAIError::ApiError(ApiError {
+            message: "failed".to_string(),
+            r#type: r#type.map(str::to_string),
+            param: None,
+            code: code.map(str::to_string),
+        })
+        .into()
+    }
+
+    #[test]
+    fn classifies_transient_openai_errors() {
+        assert!(matches!(
+            api_error(Some("requests"), Some("rate_limit_exceeded")),
+            Error::LlmUnavailable { .. }
+        ));
+        assert!(matches!(
+            api_error(None, None),
+            Error::LlmUnavailable { .. }
+        ));
+        assert!(matches!(
+            api_error(
+                Some("invalid_request_error"),
+                Some("context_length_exceeded")
+            ),
+            Error::Llm(_)
+        ));
+
+        let stream = |message: &str| Error::from(OpenAIError::StreamError(message.to_string()));
+        assert!(matches!(
+            stream("Invalid status code: 429 Too Many Requests"),
+            Error::LlmUnavailable { .. }
+        ));
+        assert!(matches!(
+            stream("Invalid status code: 401 Unauthorized"),
+            Error::Llm(_)
+        ));
+    }
+
+    #[test]
+    fn names_the_failing_component() {
+        assert_eq!(
+            Error::transport("fetch", "connection refused").to_string(),
+            "mcp [fetch] transport error: connection refused"
+        );
+        assert_eq!(
+            Error::tool("search", "tool does not exist").to_string(),
+            "tool [search] error: tool does not exist"
+        );
+        assert_eq!(
+            Error::from(LoopLimit::MaxTurns(3)).to_string(),
+            "agent loop stopped: reached the maximum of 3 turns"
+        );
+    }
+}
@@ -1,6 +1,9 @@
 pub mod agent;
+pub mod error;
+pub use error::{Error, Result};
 pub mod llm;
 pub mod mcp_server;
+pub mod session;
 
 pub fn add(left: u64, right: u64) -> u64 {
     left + right
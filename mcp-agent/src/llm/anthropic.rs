@@ -0,0 +1,505 @@
+use crate::error::{Error, Result};
+use crate::llm::llm::LLM;
+use crate::llm::message::{ChatResponse, Conversation, Message, ToolCall, ToolDefinition, Usage};
+use crate::llm::params::{GenerationParams, ToolChoice};
+use async_trait::async_trait;
+use serde::{Deserialize, Serialize};
+use serde_json::{Map, Value};
+use std::time::Duration;
+
+const BASE_URL: &str = "https://api.anthropic.com/v1";
+const API_VERSION: &str = "2023-06-01";
+
+/// Represents an Anthropic client for the Messages API
+#[derive(Debug)]
+pub struct Anthropic {
+    api_key: String,
+    base_url: String,
+    model: String,
+
+    tools: Vec<AnthropicTool>,
+    params: GenerationParams,
+    client: reqwest::Client,
+}
+
+impl Default for Anthropic {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Anthropic {
+    /// Creates a new instance of Anthropic with default values
+    pub fn new() -> Self {
+        Self {
+            api_key: Default::default(),
+            base_url: BASE_URL.to_string(),
+            model: Default::default(),
+
+            tools: Vec::new(),
+            params: GenerationParams::default(),
+            client: reqwest::Client::new(),
+        }
+    }
+
+    /// Applies a single configuration option to the Anthropic instance
+    pub fn with_option(&mut self, opt: AnthropicOption) -> &Self {
+        opt(self);
+        self
+    }
+
+    /// Applies multiple configuration options to the Anthropic instance
+    pub fn with_options(&mut self, opts: Vec<AnthropicOption>) -> &Self {
+        for func in opts {
+            func(self);
+        }
+        self
+    }
+}
+
+impl Anthropic {
+    /// Builds the Messages API request for the conversation
+    ///
+    /// `seed` and the penalties have no equivalent and are ignored.
+    fn build_request<'a>(
+        &'a self,
+        conversation: &Conversation,
+        params: GenerationParams,
+    ) -> MessagesRequest<'a> {
+        let mut system = Vec::new();
+        let mut messages: Vec<AnthropicMessage> = Vec::new();
+
+        for message in &conversation.messages {
+            let (role, blocks) = match message {
+                Message::System { content } => {
+                    system.push(content.clone());
+                    continue;
+                }
+                Message::User { content } => (USER_ROLE, vec![ContentBlock::text(content)]),
+                Message::Assistant {
+                    content,
+                    tool_calls,
+                } => {
+                    let mut blocks = Vec::new();
+                    if let Some(content) = content.as_ref().filter(|c| !c.is_empty()) {
+                        blocks.push(ContentBlock::text(content));
+                    }
+                    blocks.extend(tool_calls.iter().map(|call| ContentBlock::ToolUse {
+                        id: call.id.clone(),
+                        name: call.name.clone(),
+                        input: parse_arguments(&call.arguments),
+                    }));
+                    (ASSISTANT_ROLE, blocks)
+                }
+                Message::Tool {
+                    tool_call_id,
+                    content,
+                } => (
+                    USER_ROLE,
+                    vec![ContentBlock::ToolResult {
+                        tool_use_id: tool_call_id.clone(),
+                        content: content.clone(),
+                    }],
+                ),
+            };
+
+            // The API expects alternating roles, e.g. all the tool results of a turn
+            // go into a single user message
+            match messages.last_mut() {
+                Some(last) if last.role == role => last.content.extend(blocks),
+                _ => messages.push(AnthropicMessage {
+                    role,
+                    content: blocks,
+                }),
+            }
+        }
+
+        // The Messages API has no response format, it is requested in the prompt
+        system.extend(conversation.response_format.instructions());
+
+        let tool_choice = if self.tools.is_empty() {
+            None
+        } else {
+            to_anthropic_tool_choice(params.tool_choice, params.parallel_tool_calls)
+        };
+
+        MessagesRequest {
+            model: &self.model,
+            max_tokens: conversation.max_tokens,
+            system: (!system.is_empty()).then(|| system.join("\n\n")),
+            messages,
+            tools: &self.tools,
+            tool_choice,
+            temperature: params.temperature,
+            top_p: params.top_p,
+            stop_sequences: params.stop,
+            metadata: params.user.map(|user_id| Metadata { user_id }),
+            extra: params.extra,
+        }
+    }
+}
+
+#[async_trait]
+impl LLM for Anthropic {
+    /// Sends a conversation to Anthropic and returns the chat response
+    async fn send(&self, conversation: Conversation) -> Result<ChatResponse> {
+        let request = self.build_request(&conversation, self.params.merge(&conversation.params));
+
+        let response = self
+            .client
+            .post(format!("{}/messages", self.base_url))
+            .header("x-api-key", &self.api_key)
+            .header("anthropic-version", API_VERSION)
+            .json(&request)
+            .send()
+            .await
+            .inspect_err(|err| {
+                println!("Failed to send Anthropic messages request: {}", err);
+            })?;
+
+        let status = response.status();
+        if !status.is_success() {
+            let retry_after = response
+                .headers()
+                .get(reqwest::header::RETRY_AFTER)
+                .and_then(|value| value.to_str().ok())
+                .and_then(|value| value.trim().parse::<u64>().ok())
+                .map(Duration::from_secs);
+            let body = response.text().await.unwrap_or_default();
+            let message = format!("{}: {}", status, body);
+            // 529 is returned when the API is overloaded
+            if status.as_u16() == 429 || status.is_server_error() {
+                return Err(Error::llm_unavailable(message, retry_after));
+            }
+            return Err(Error::Llm(message));
+        }
+
+        let response: MessagesResponse = response.json().await?;
+        println!("Sending response {:?}", &response);
+
+        let mut resp = ChatResponse::new(conversation);
+        resp.usage = response.usage.map(AnthropicUsage::into_usage);
+        let mut text = String::new();
+        for block in response.content {
+            match block {
+                ContentBlock::Text { text: part } => text.push_str(&part),
+                ContentBlock::ToolUse { id, name, input } => {
+                    resp.tool_calls.get_or_insert_with(Vec::new).push(ToolCall {
+                        id,
+                        name,
+                        arguments: input.to_string(),
+                    })
+                }
+                ContentBlock::ToolResult { .. } | ContentBlock::Other => {}
+            }
+        }
+
+        if !text.is_empty() {
+            resp.conversation.append_assistant_content(text.clone());
+            resp.content = Some(text);
+        }
+
+        Ok(resp)
+    }
+}
+
+const USER_ROLE: &str = "user";
+const ASSISTANT_ROLE: &str = "assistant";
+
+#[derive(Debug, Serialize)]
+struct MessagesRequest<'a> {
+    model: &'a str,
+    max_tokens: u32,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    system: Option<String>,
+    messages: Vec<AnthropicMessage>,
+    #[serde(skip_serializing_if = "<[_]>::is_empty")]
+    tools: &'a [AnthropicTool],
+    #[serde(skip_serializing_if = "Option::is_none")]
+    tool_choice: Option<AnthropicToolChoice>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    temperature: Option<f32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    top_p: Option<f32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    stop_sequences: Option<Vec<String>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    metadata: Option<Metadata>,
+    #[serde(flatten)]
+    extra: Map<String, Value>,
+}
+
+#[derive(Debug, Serialize)]
+struct Metadata {
+    user_id: String,
+}
+
+#[derive(Debug, Serialize)]
+struct AnthropicToolChoice {
+    r#type: &'static str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    name: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    disable_parallel_tool_use: Option<bool>,
+}
+
+#[derive(Debug, Serialize)]
+struct AnthropicMessage {
+    role: &'static str,
+    content: Vec<ContentBlock>,
+}
+
+#[derive(Debug, Serialize)]
+struct AnthropicTool {
+    name: String,
+    description: String,
+    input_schema: Value,
+}
+
+#[derive(Debug, Deserialize)]
+struct MessagesResponse {
+    content: Vec<ContentBlock>,
+    usage: Option<AnthropicUsage>,
+}
+
+#[derive(Debug, Deserialize)]
+struct AnthropicUsage {
+    input_tokens: u32,
+    output_tokens: u32,
+    #[serde(default)]
+    cache_creation_input_tokens: Option<u32>,
+    #[serde(default)]
+    cache_read_input_tokens: Option<u32>,
+}
+
+impl AnthropicUsage {
+    /// `input_tokens` excludes the tokens read from or written to the cache
+    fn into_usage(self) -> Usage {
+        let cached_tokens = self.cache_read_input_tokens.unwrap_or_default();
+        Usage {
+            prompt_tokens: self.input_tokens
+                + cached_tokens
+                + self.cache_creation_input_tokens.unwrap_or_default(),
+            completion_tokens: self.output_tokens,
+            cached_tokens,
+        }
+    }
+}
+
+/// A content block of a message, either sent or received
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum ContentBlock {
+    Text {
+        text: String,
+    },
+    ToolUse {
+        id: String,
+        name: String,
+        input: Value,
+    },
+    ToolResult {
+        tool_use_id: String,
+        content: String,
+    },
+    /// Blocks this client does not handle, e.g. thinking
+    #[serde(other)]
+    Other,
+}
+
+impl ContentBlock {
+    fn text(text: &str) -> Self {
+        ContentBlock::Text {
+            text: text.to_string(),
+        }
+    }
+}
+
+/// Converts the tool choice, `parallel_tool_calls` is part of it in the Messages API
+fn to_anthropic_tool_choice(
+    tool_choice: Option<ToolChoice>,
+    parallel_tool_calls: Option<bool>,
+) -> Option<AnthropicToolChoice> {
+    if tool_choice.is_none() && parallel_tool_calls.is_none() {
+        return None;
+    }
+
+    let (r#type, name) = match tool_choice.unwrap_or(ToolChoice::Auto) {
+        ToolChoice::Auto => ("auto", None),
+        ToolChoice::None => ("none", None),
+        ToolChoice::Required => ("any", None),
+        ToolChoice::Function(name) => ("tool", Some(name)),
+    };
+    Some(AnthropicToolChoice {
+        r#type,
+        name,
+        disable_parallel_tool_use: parallel_tool_calls
+            .filter(|_| r#type != "none")
+            .map(|parallel| !parallel),
+    })
+}
+
+/// Parses the JSON arguments of a tool call, the API requires an object
+fn parse_arguments(arguments: &str) -> Value {
+    serde_json::from_str::<Value>(arguments)
+        .ok()
+        .filter(Value::is_object)
+        .unwrap_or_else(|| Value::Object(Default::default()))
+}
+
+/// Type alias for Anthropic configuration options
+pub type AnthropicOption = Box<dyn FnOnce(&mut Anthropic)>;
+
+/// Creates an option to set the API key
+pub fn with_api_key(api_key: &str) -> AnthropicOption {
+    let api_key = api_key.to_string();
+    Box::new(move |anthropic| anthropic.api_key = api_key)
+}
+
+/// Creates an option to set the model
+pub fn with_model(model: &str) -> AnthropicOption {
+    let model = model.to_string();
+    Box::new(move |anthropic| anthropic.model = model)
+}
+
+/// Creates an option to set the base URL
+pub fn with_base_url(base_url: &str) -> AnthropicOption {
+    let base_url = base_url.trim_end_matches('/').to_string();
+    Box::new(move |anthropic| anthropic.base_url = base_url)
+}
+
+/// Creates an option to set the default generation parameters
+pub fn with_params(params: GenerationParams) -> AnthropicOption {
+    Box::new(move |anthropic| anthropic.params = params)
+}
+
+/// Creates an option to set the tools the model can use
+pub fn with_tools(tools: Vec<ToolDefinition>) -> AnthropicOption {
+    Box::new(move |anthropic| {
+        anthropic.tools = tools
+            .into_iter()
+            .map(|tool| AnthropicTool {
+                name: tool.name,
+                description: tool.description,
+                input_schema: tool.parameters,
+            })
+            .collect()
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::Json;
+    use axum::Router;
+    use axum::extract::State;
+    use axum::http::HeaderMap;
+    use axum::routing::post;
+    use serde_json::json;
+    use std::sync::{Arc, Mutex};
+
+    #[tokio::test]
+    async fn sends_tool_results_and_parses_tool_use() {
+        let captured = Arc::new(Mutex::new(None));
+        let app = Router::new()
+            .route(
+                "/v1/messages",
+                post(
+                    |State(captured): State<Arc<Mutex<Option<Value>>>>,
+                     headers: HeaderMap,
+                     Json(body): Json<Value>| async move {
+                        assert_eq!(headers["x-api-key"], "test-key");
+                        assert_eq!(headers["anthropic-version"], API_VERSION);
+                        *captured.lock().unwrap() = Some(body);
+                        Json(json!({
+                            "id": "msg_1",
+                            "type": "message",
+                            "role": "assistant",
+                            "content": [
+                                {"type": "text", "text": "Let me check."},
+                                {"type": "tool_use", "id": "toolu_2", "name": "weather", "input": {"city": "Paris"}}
+                            ],
+                            "stop_reason": "tool_use",
+                            "usage": {"input_tokens": 40, "output_tokens": 12, "cache_read_input_tokens": 100}
+                        }))
+                    },
+                ),
+            )
+            .with_state(captured.clone());
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move { axum::serve(listener, app).await });
+
+        let mut llm = Anthropic::new();
+        llm.with_options(vec![
+            with_api_key("test-key"),
+            with_model("claude-test"),
+            with_base_url(&format!("http://{}/v1", addr)),
+            with_tools(vec![ToolDefinition {
+                name: "weather".to_string(),
+                description: "Weather of a city".to_string(),
+                parameters: json!({"type": "object"}),
+            }]),
+        ]);
+
+        let mut conversation = Conversation::new_with_prompt(256, "Be brief".to_string());
+        conversation.append_user_content("Weather in Paris and Rome?".to_string());
+        conversation.append_tool_call_response(&[
+            ToolCall {
+                id: "toolu_0".to_string(),
+                name: "weather".to_string(),
+                arguments: r#"{"city":"Rome"}"#.to_string(),
+            },
+            ToolCall {
+                id: "toolu_1".to_string(),
+                name: "weather".to_string(),
+                arguments: "not json".to_string(),
+            },
+        ]);
+        conversation.append_tool_call_content("sunny".to_string(), "toolu_0".to_string());
+        conversation.append_tool_call_content("rainy".to_string(), "toolu_1".to_string());
+
+        let response = llm.send(conversation).await.unwrap();
+        assert_eq!(response.content.as_deref(), Some("Let me check."));
+        assert_eq!(
+            response.tool_calls,
+            Some(vec![ToolCall {
+                id: "toolu_2".to_string(),
+                name: "weather".to_string(),
+                arguments: r#"{"city":"Paris"}"#.to_string(),
+            }])
+        );
+        assert_eq!(
+            response.usage,
+            Some(Usage {
+                prompt_tokens: 140,
+                completion_tokens: 12,
+                cached_tokens: 100,
+            })
+        );
+
+        let body = captured.lock().unwrap().take().unwrap();
+        assert_eq!(
+            body,
+            json!({
+                "model": "claude-test",
+                "max_tokens": 256,
+                "system": "Be brief",
+                "messages": [
+                    {"role": "user", "content": [{"type": "text", "text": "Weather in Paris and Rome?"}]},
+                    {"role": "assistant", "content": [
+                        {"type": "tool_use", "id": "toolu_0", "name": "weather", "input": {"city": "Rome"}},
+                        {"type": "tool_use", "id": "toolu_1", "name": "weather", "input": {}}
+                    ]},
+                    {"role": "user", "content": [
+                        {"type": "tool_result", "tool_use_id": "toolu_0", "content": "sunny"},
+                        {"type": "tool_result", "tool_use_id": "toolu_1", "content": "rainy"}
+                    ]}
+                ],
+                "tools": [
+                    {"name": "weather", "description": "Weather of a city", "input_schema": {"type": "object"}}
+                ]
+            })
+        );
+    }
+}
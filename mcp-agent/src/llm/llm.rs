@@ -1,7 +1,45 @@
-use crate::llm::message::{ChatResponse, Conversation};
+use crate::error::Result;
+use crate::llm::message::{ChatDelta, ChatResponse, ChatStream, Conversation};
 use async_trait::async_trait;
+use futures::stream;
 
 #[async_trait]
 pub trait LLM: Send + Sync {
-    async fn send(&self, conversation: Conversation) -> ChatResponse;
+    /// Sends a conversation to the LLM and returns its response
+    async fn send(&self, conversation: Conversation) -> Result<ChatResponse>;
+
+    /// Sends a conversation to the LLM and returns its response as a stream of deltas
+    ///
+    /// The default implementation sends the conversation with [`LLM::send`] and yields
+    /// the whole response at once.
+    async fn send_stream(&self, conversation: Conversation) -> Result<ChatStream> {
+        Ok(response_stream(self.send(conversation).await?))
+    }
+}
+
+/// Converts a whole chat response into a stream of deltas
+pub fn response_stream(response: ChatResponse) -> ChatStream {
+    let mut deltas = Vec::new();
+    if let Some(content) = response.content {
+        deltas.push(Ok(ChatDelta::Content(content)));
+    }
+    for (index, call) in response
+        .tool_calls
+        .unwrap_or_default()
+        .into_iter()
+        .enumerate()
+    {
+        deltas.push(Ok(ChatDelta::ToolCall {
+            index: index as u32,
+            id: Some(call.id),
+            name: Some(call.name),
+            arguments: call.arguments,
+        }));
+    }
+
+    if let Some(usage) = response.usage {
+        deltas.push(Ok(ChatDelta::Usage(usage)));
+    }
+
+    Box::pin(stream::iter(deltas))
 }
@@ -1,15 +1,101 @@
-use async_openai::types::{
-    ChatCompletionMessageToolCall, ChatCompletionRequestAssistantMessageArgs,
-    ChatCompletionRequestMessage, ChatCompletionRequestSystemMessageArgs,
-    ChatCompletionRequestToolMessageArgs, ChatCompletionRequestUserMessageArgs,
-};
+use crate::error::{Error, Result};
+use crate::llm::params::GenerationParams;
+use crate::llm::template::PromptTemplates;
+use crate::llm::tokens::TokenCounter;
+use futures::Stream;
 use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::collections::BTreeMap;
+use std::pin::Pin;
+
+/// A message of a conversation, independent of the LLM provider
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "role", rename_all = "snake_case")]
+pub enum Message {
+    /// Instructions for the LLM
+    System { content: String },
+    /// A message of the user
+    User { content: String },
+    /// A response of the LLM, with its text and the tools it wants to call
+    Assistant {
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        content: Option<String>,
+        #[serde(default, skip_serializing_if = "Vec::is_empty")]
+        tool_calls: Vec<ToolCall>,
+    },
+    /// The result of a tool call
+    Tool {
+        tool_call_id: String,
+        content: String,
+    },
+}
+
+/// A tool call requested by the LLM
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ToolCall {
+    pub id: String,
+    pub name: String,
+    /// Arguments of the call as a JSON string
+    pub arguments: String,
+}
+
+/// A tool the LLM can call
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ToolDefinition {
+    pub name: String,
+    pub description: String,
+    /// JSON schema of the tool arguments
+    pub parameters: Value,
+}
+
+/// The format the LLM is asked to answer in
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum ResponseFormat {
+    /// Free text
+    #[default]
+    Text,
+    /// Any JSON object
+    JsonObject,
+    /// A JSON value matching the schema (structured outputs)
+    JsonSchema {
+        name: String,
+        schema: Value,
+        /// Whether the provider must enforce the schema, which restricts the schema features
+        #[serde(default)]
+        strict: bool,
+    },
+}
+
+impl ResponseFormat {
+    /// Returns prompt instructions for providers that cannot enforce the format
+    pub fn instructions(&self) -> Option<String> {
+        match self {
+            ResponseFormat::Text => None,
+            ResponseFormat::JsonObject => {
+                Some("Reply with only a JSON object and nothing else.".to_string())
+            }
+            ResponseFormat::JsonSchema { schema, .. } => Some(format!(
+                "Reply with only a JSON value matching this JSON schema and nothing else:\n{}",
+                schema
+            )),
+        }
+    }
+}
+
+const DEFAULT_TEMPLATE_MAX_TOKENS: u32 = 4096;
 
 /// Represents a conversation with a list of messages and maximum token limit
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Conversation {
-    pub messages: Vec<ChatCompletionRequestMessage>,
+    pub messages: Vec<Message>,
     pub max_tokens: u32,
+    /// Format of the next responses of the LLM
+    #[serde(default)]
+    pub response_format: ResponseFormat,
+    /// Generation parameters overriding the ones of the LLM configuration
+    #[serde(default)]
+    pub params: GenerationParams,
 }
 
 impl Conversation {
@@ -18,9 +104,23 @@ impl Conversation {
         Self {
             messages: Vec::new(),
             max_tokens,
+            response_format: ResponseFormat::default(),
+            params: GenerationParams::default(),
         }
     }
 
+    /// Sets the generation parameters overriding the ones of the LLM configuration
+    pub fn with_params(mut self, params: GenerationParams) -> Self {
+        self.params = params;
+        self
+    }
+
+    /// Sets the format of the next responses of the LLM
+    pub fn with_response_format(mut self, response_format: ResponseFormat) -> Self {
+        self.response_format = response_format;
+        self
+    }
+
     /// Creates a new conversation with a system prompt and maximum tokens
     pub fn new_with_prompt(max_tokens: u32, system_prompt: String) -> Self {
         let mut conversation = Self::new(max_tokens);
@@ -28,72 +128,274 @@ impl Conversation {
         conversation
     }
 
+    /// Creates a conversation from the prompt template with the name, see
+    /// [`Conversation::append_template`]
+    ///
+    /// Conversations are limited to 4096 tokens unless the template sets `max_tokens`.
+    pub fn from_template(
+        templates: &PromptTemplates,
+        name: &str,
+        vars: &[(&str, &str)],
+    ) -> Result<Self> {
+        let mut conversation = Self::new(DEFAULT_TEMPLATE_MAX_TOKENS);
+        conversation.append_template(templates, name, vars)?;
+        Ok(conversation)
+    }
+
+    /// Appends the prompts of the template rendered with the variables, and applies
+    /// its `max_tokens` and `response_format`
+    ///
+    /// The system prompt is only appended when the conversation has none, so that a
+    /// conversation can go on with the same template.
+    pub fn append_template(
+        &mut self,
+        templates: &PromptTemplates,
+        name: &str,
+        vars: &[(&str, &str)],
+    ) -> Result<()> {
+        let (system, user) = templates.render(name, vars)?;
+        let prompt = templates.get(name)?;
+
+        let has_system = self
+            .messages
+            .iter()
+            .any(|message| matches!(message, Message::System { .. }));
+        if let Some(system) = system.filter(|_| !has_system) {
+            self.append_system_content(system);
+        }
+        if let Some(user) = user {
+            self.append_user_content(user);
+        }
+        if let Some(max_tokens) = prompt.max_tokens {
+            self.max_tokens = max_tokens;
+        }
+        if let Some(response_format) = &prompt.response_format {
+            self.response_format = response_format.clone();
+        }
+        Ok(())
+    }
+
     /// Appends a raw message to the conversation
-    pub fn append_message(&mut self, message: ChatCompletionRequestMessage) {
+    pub fn append_message(&mut self, message: Message) {
         self.messages.push(message);
     }
 
     /// Appends a user message with the given content
     pub fn append_user_content(&mut self, content: String) {
-        self.messages.push(
-            ChatCompletionRequestUserMessageArgs::default()
-                .content(content)
-                .build()
-                .unwrap()
-                .into(),
-        )
+        self.messages.push(Message::User { content })
     }
 
     /// Appends a system message with the given content
     pub fn append_system_content(&mut self, content: String) {
-        self.messages.push(
-            ChatCompletionRequestSystemMessageArgs::default()
-                .content(content)
-                .build()
-                .unwrap()
-                .into(),
-        )
+        self.messages.push(Message::System { content })
     }
 
     /// Appends an assistant message with tool calls
-    pub fn append_tool_call_response(&mut self, tool_calls: &Vec<ChatCompletionMessageToolCall>) {
-        self.messages.push(
-            ChatCompletionRequestAssistantMessageArgs::default()
-                .tool_calls(tool_calls.clone())
-                .build()
-                .unwrap()
-                .into(),
-        )
+    pub fn append_tool_call_response(&mut self, tool_calls: &[ToolCall]) {
+        self.messages.push(Message::Assistant {
+            content: None,
+            tool_calls: tool_calls.to_vec(),
+        })
     }
 
     /// Appends an assistant message with the given content
     pub fn append_assistant_content(&mut self, content: String) {
-        self.messages.push(
-            ChatCompletionRequestAssistantMessageArgs::default()
-                .content(content)
-                .build()
-                .unwrap()
-                .into(),
-        )
+        self.messages.push(Message::Assistant {
+            content: Some(content),
+            tool_calls: Vec::new(),
+        })
     }
 
     /// Appends a tool message with content and tool ID
     pub fn append_tool_call_content(&mut self, content: String, tool_id: String) {
-        self.messages.push(
-            ChatCompletionRequestToolMessageArgs::default()
-                .content(content)
-                .tool_call_id(tool_id)
-                .build()
-                .unwrap()
-                .into(),
-        )
+        self.messages.push(Message::Tool {
+            tool_call_id: tool_id,
+            content,
+        })
+    }
+
+    /// Returns a checkpoint of the conversation as it is now, see [`Conversation::rewind_to`]
+    pub fn checkpoint(&self) -> Checkpoint {
+        Checkpoint {
+            position: self.messages.len(),
+            last: self.messages.last().cloned(),
+        }
+    }
+
+    /// Removes the messages appended since the checkpoint
+    ///
+    /// Fails when the messages before the checkpoint changed since, e.g. when a
+    /// context strategy dropped them, or when the checkpoint was taken between a
+    /// tool call and its results.
+    pub fn rewind_to(&mut self, checkpoint: &Checkpoint) -> Result<()> {
+        let matches = match checkpoint.position.checked_sub(1) {
+            Some(index) => self.messages.get(index) == checkpoint.last.as_ref(),
+            None => true,
+        };
+        if !matches {
+            return Err(Error::Conversation(
+                "the conversation changed before the checkpoint".to_string(),
+            ));
+        }
+        check_boundary(&self.messages, checkpoint.position)?;
+        self.messages.truncate(checkpoint.position);
+        Ok(())
+    }
+
+    /// Returns an independent branch with the messages before `index` and the
+    /// settings of this conversation
+    ///
+    /// Fails when `index` is out of range or separates a tool call from its results.
+    pub fn fork(&self, index: usize) -> Result<Conversation> {
+        check_boundary(&self.messages, index)?;
+        Ok(Conversation {
+            messages: self.messages[..index].to_vec(),
+            ..self.clone_settings()
+        })
+    }
+
+    /// Replaces the result of a tool call and removes the messages following its
+    /// results, which were written from the previous result
+    ///
+    /// The conversation can then be sent again to continue from the new result.
+    pub fn replace_tool_result(&mut self, tool_call_id: &str, content: String) -> Result<()> {
+        let index = self
+            .messages
+            .iter()
+            .rposition(|message| {
+                matches!(message, Message::Tool { tool_call_id: id, .. } if id == tool_call_id)
+            })
+            .ok_or_else(|| {
+                Error::Conversation(format!("no result for tool call {}", tool_call_id))
+            })?;
+
+        let end = self.messages[index..]
+            .iter()
+            .position(|message| !matches!(message, Message::Tool { .. }))
+            .map_or(self.messages.len(), |offset| index + offset);
+        self.messages.truncate(end);
+        self.messages[index] = Message::Tool {
+            tool_call_id: tool_call_id.to_string(),
+            content,
+        };
+        Ok(())
+    }
+
+    /// Returns an empty conversation with the settings of this one
+    fn clone_settings(&self) -> Conversation {
+        Conversation {
+            messages: Vec::new(),
+            max_tokens: self.max_tokens,
+            response_format: self.response_format.clone(),
+            params: self.params.clone(),
+        }
+    }
+
+    /// Returns the tokens of the conversation sent with the tools, see [`TokenCounter`]
+    pub fn token_count(&self, counter: &dyn TokenCounter, tools: &[ToolDefinition]) -> usize {
+        counter.count_messages(&self.messages) + counter.count_tools(tools)
+    }
+
+    /// Returns the tokens of each message
+    pub fn message_token_counts(&self, counter: &dyn TokenCounter) -> Vec<usize> {
+        self.messages
+            .iter()
+            .map(|message| counter.count_message(message))
+            .collect()
+    }
+}
+
+/// A position of a conversation to rewind to
+#[derive(Debug, Clone, PartialEq)]
+pub struct Checkpoint {
+    position: usize,
+    /// Last message before the checkpoint, to detect conversations rewritten since
+    last: Option<Message>,
+}
+
+impl Checkpoint {
+    /// Returns the number of messages before the checkpoint
+    pub fn position(&self) -> usize {
+        self.position
+    }
+}
+
+/// Checks that the messages before `index` do not end with tool calls missing
+/// some of their results
+fn check_boundary(messages: &[Message], index: usize) -> Result<()> {
+    if index > messages.len() {
+        return Err(Error::Conversation(format!(
+            "message index {} is out of range, the conversation has {} messages",
+            index,
+            messages.len()
+        )));
+    }
+
+    let prefix = &messages[..index];
+    let last_calls = prefix
+        .iter()
+        .enumerate()
+        .rev()
+        .find_map(|(index, message)| match message {
+            Message::Assistant { tool_calls, .. } if !tool_calls.is_empty() => {
+                Some((index, tool_calls))
+            }
+            _ => None,
+        });
+    let Some((calls_index, tool_calls)) = last_calls else {
+        return Ok(());
+    };
+
+    let results = &prefix[calls_index + 1..];
+    let answered = |call: &ToolCall| {
+        results.iter().any(|message| match message {
+            Message::Tool { tool_call_id, .. } => *tool_call_id == call.id,
+            _ => false,
+        })
+    };
+    match tool_calls.iter().find(|call| !answered(call)) {
+        Some(call) => Err(Error::Conversation(format!(
+            "message index {} separates tool call {} from its result",
+            index, call.id
+        ))),
+        None => Ok(()),
+    }
+}
+
+/// Tokens consumed by an LLM request
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Usage {
+    /// Tokens of the prompt, including the cached ones
+    pub prompt_tokens: u32,
+    pub completion_tokens: u32,
+    /// Tokens of the prompt read from the provider cache
+    pub cached_tokens: u32,
+}
+
+impl Usage {
+    /// Returns the number of prompt and completion tokens
+    pub fn total_tokens(&self) -> u32 {
+        self.prompt_tokens.saturating_add(self.completion_tokens)
+    }
+}
+
+impl std::ops::AddAssign for Usage {
+    fn add_assign(&mut self, other: Usage) {
+        self.prompt_tokens = self.prompt_tokens.saturating_add(other.prompt_tokens);
+        self.completion_tokens = self
+            .completion_tokens
+            .saturating_add(other.completion_tokens);
+        self.cached_tokens = self.cached_tokens.saturating_add(other.cached_tokens);
     }
 }
 
 /// Represents a response from the chat model, including the conversation and optional tool calls
 pub struct ChatResponse {
     pub conversation: Conversation,
-    pub tool_calls: Option<Vec<ChatCompletionMessageToolCall>>,
+    pub content: Option<String>,
+    pub tool_calls: Option<Vec<ToolCall>>,
+    /// Tokens consumed by the request, when reported by the provider
+    pub usage: Option<Usage>,
 }
 
 impl ChatResponse {
@@ -101,7 +403,199 @@ impl ChatResponse {
     pub fn new(conversation: Conversation) -> Self {
         Self {
             conversation,
+            content: None,
             tool_calls: None,
+            usage: None,
+        }
+    }
+}
+
+/// A chunk of a streamed chat response
+#[derive(Debug, Clone, PartialEq)]
+pub enum ChatDelta {
+    /// A piece of the assistant text
+    Content(String),
+    /// A piece of a tool call, chunks of the same tool call share the same index
+    ToolCall {
+        index: u32,
+        id: Option<String>,
+        name: Option<String>,
+        arguments: String,
+    },
+    /// Tokens consumed by the request, usually sent with the last chunk
+    Usage(Usage),
+}
+
+/// Stream of chunks of a chat response
+pub type ChatStream = Pin<Box<dyn Stream<Item = Result<ChatDelta>> + Send>>;
+
+/// Collects the chunks of a streamed chat response into a [`ChatResponse`]
+#[derive(Default)]
+pub struct ChatDeltaAccumulator {
+    content: String,
+    tool_calls: BTreeMap<u32, ToolCall>,
+    usage: Option<Usage>,
+}
+
+impl ChatDeltaAccumulator {
+    /// Adds a chunk to the response
+    pub fn push(&mut self, delta: ChatDelta) {
+        match delta {
+            ChatDelta::Content(content) => self.content.push_str(&content),
+            ChatDelta::ToolCall {
+                index,
+                id,
+                name,
+                arguments,
+            } => {
+                let call = self.tool_calls.entry(index).or_insert_with(|| ToolCall {
+                    id: String::new(),
+                    name: String::new(),
+                    arguments: String::new(),
+                });
+                if let Some(id) = id {
+                    call.id.push_str(&id);
+                }
+                if let Some(name) = name {
+                    call.name.push_str(&name);
+                }
+                call.arguments.push_str(&arguments);
+            }
+            ChatDelta::Usage(usage) => self.usage = Some(usage),
+        }
+    }
+
+    /// Builds the response, the assistant text is appended to the conversation
+    pub fn finish(self, conversation: Conversation) -> ChatResponse {
+        let mut resp = ChatResponse::new(conversation);
+        if !self.content.is_empty() {
+            resp.conversation
+                .append_assistant_content(self.content.clone());
+            resp.content = Some(self.content);
+        }
+        if !self.tool_calls.is_empty() {
+            resp.tool_calls = Some(self.tool_calls.into_values().collect());
         }
+        resp.usage = self.usage;
+        resp
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn branches_keep_tool_calls_with_their_results() {
+        let mut conversation = Conversation::new_with_prompt(256, "Be brief".to_string());
+        conversation.append_user_content("weather in Paris?".to_string());
+        let checkpoint = conversation.checkpoint();
+        conversation.append_tool_call_response(&[ToolCall {
+            id: "call_0".to_string(),
+            name: "weather".to_string(),
+            arguments: "{}".to_string(),
+        }]);
+        assert!(conversation.fork(3).is_err());
+        conversation.append_tool_call_content("sunny".to_string(), "call_0".to_string());
+        conversation.append_assistant_content("It is sunny".to_string());
+
+        let branch = conversation.fork(4).unwrap();
+        assert_eq!(branch.messages, conversation.messages[..4]);
+        assert!(conversation.fork(6).is_err());
+
+        conversation
+            .replace_tool_result("call_0", "rainy".to_string())
+            .unwrap();
+        assert_eq!(conversation.messages.len(), 4);
+        assert_eq!(
+            conversation.messages[3],
+            Message::Tool {
+                tool_call_id: "call_0".to_string(),
+                content: "rainy".to_string()
+            }
+        );
+        assert!(
+            conversation
+                .replace_tool_result("call_1", String::new())
+                .is_err()
+        );
+
+        conversation.rewind_to(&checkpoint).unwrap();
+        assert_eq!(conversation.messages, branch.messages[..2]);
+
+        conversation.messages.remove(1);
+        assert!(conversation.rewind_to(&checkpoint).is_err());
+    }
+
+    #[test]
+    fn response_format_defaults_to_text() {
+        let conversation: Conversation =
+            serde_json::from_value(json!({"messages": [], "max_tokens": 16})).unwrap();
+        assert_eq!(conversation.response_format, ResponseFormat::Text);
+
+        let format: ResponseFormat = serde_json::from_value(json!({
+            "type": "json_schema",
+            "name": "answer",
+            "schema": {"type": "object"}
+        }))
+        .unwrap();
+        assert_eq!(
+            format,
+            ResponseFormat::JsonSchema {
+                name: "answer".to_string(),
+                schema: json!({"type": "object"}),
+                strict: false,
+            }
+        );
+    }
+
+    #[test]
+    fn accumulator_assembles_tool_call_chunks() {
+        let mut acc = ChatDeltaAccumulator::default();
+        acc.push(ChatDelta::Content("Looking ".to_string()));
+        acc.push(ChatDelta::ToolCall {
+            index: 1,
+            id: Some("call_2".to_string()),
+            name: Some("search".to_string()),
+            arguments: "{\"q\":".to_string(),
+        });
+        acc.push(ChatDelta::ToolCall {
+            index: 0,
+            id: Some("call_1".to_string()),
+            name: Some("fetch".to_string()),
+            arguments: "{}".to_string(),
+        });
+        acc.push(ChatDelta::Content("it up".to_string()));
+        acc.push(ChatDelta::ToolCall {
+            index: 1,
+            id: None,
+            name: None,
+            arguments: "\"gas\"}".to_string(),
+        });
+
+        let resp = acc.finish(Conversation::new(16));
+        assert_eq!(resp.content.as_deref(), Some("Looking it up"));
+        assert_eq!(resp.conversation.messages.len(), 1);
+
+        let calls = resp.tool_calls.unwrap();
+        assert_eq!(calls.len(), 2);
+        assert_eq!(calls[0].id, "call_1");
+        assert_eq!(calls[1].name, "search");
+        assert_eq!(calls[1].arguments, "{\"q\":\"gas\"}");
+    }
+
+    #[test]
+    fn usage_saturates_instead_of_overflowing() {
+        let mut usage = Usage {
+            prompt_tokens: u32::MAX - 1,
+            completion_tokens: 10,
+            cached_tokens: 0,
+        };
+        assert_eq!(usage.total_tokens(), u32::MAX);
+
+        usage += usage;
+        assert_eq!(usage.prompt_tokens, u32::MAX);
+        assert_eq!(usage.completion_tokens, 20);
     }
 }
@@ -1,3 +1,8 @@
+pub mod anthropic;
 pub mod llm;
 pub mod message;
 pub mod openai;
+pub mod params;
+pub mod template;
+pub mod tokens;
+pub mod tool_prompt;
@@ -1,12 +1,59 @@
-use crate::llm::llm::LLM;
-use crate::llm::message::{ChatResponse, Conversation};
+use crate::error::{Error, Result};
+use crate::llm::llm::{LLM, response_stream};
+use crate::llm::message::{
+    ChatDelta, ChatResponse, ChatStream, Conversation, Message, ResponseFormat, ToolCall,
+    ToolDefinition, Usage,
+};
+use crate::llm::params::{GenerationParams, ToolChoice};
+use crate::llm::tool_prompt;
 use async_openai::Client;
 use async_openai::config::OpenAIConfig;
-use async_openai::types::{ChatCompletionTool, CreateChatCompletionRequestArgs, ResponseFormat};
+use async_openai::types::{
+    ChatCompletionMessageToolCall, ChatCompletionNamedToolChoice,
+    ChatCompletionRequestAssistantMessageArgs, ChatCompletionRequestMessage,
+    ChatCompletionRequestSystemMessageArgs, ChatCompletionRequestToolMessageArgs,
+    ChatCompletionRequestUserMessageArgs, ChatCompletionResponseStream,
+    ChatCompletionStreamOptions, ChatCompletionStreamResponseDelta, ChatCompletionTool,
+    ChatCompletionToolChoiceOption, ChatCompletionToolType, CompletionUsage,
+    CreateChatCompletionRequest, CreateChatCompletionRequestArgs, CreateChatCompletionResponse,
+    CreateChatCompletionStreamResponse, FunctionCall, FunctionName, FunctionObject,
+    ResponseFormat as OpenAiResponseFormat, ResponseFormatJsonSchema, Stop,
+};
 use async_trait::async_trait;
+use futures::{StreamExt, stream};
+use serde_json::{Map, Value, json};
+use std::time::Duration;
 
 const BASE_URL: &str = "https://api.openai.com/v1";
 
+/// Optional features of an OpenAI-compatible server
+///
+/// OpenAI supports all of them, local servers such as Ollama or vLLM may not
+/// depending on the model.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Capabilities {
+    /// Native tool calling, tools are described in the prompt otherwise
+    pub tools: bool,
+    /// `response_format` of type `json_object` or `json_schema`, the format is
+    /// requested in the prompt otherwise
+    pub json_mode: bool,
+    /// Streamed responses, whole responses are sent at once otherwise
+    pub streaming: bool,
+    /// Several tool calls in a single response
+    pub parallel_tool_calls: bool,
+}
+
+impl Default for Capabilities {
+    fn default() -> Self {
+        Self {
+            tools: true,
+            json_mode: true,
+            streaming: true,
+            parallel_tool_calls: true,
+        }
+    }
+}
+
 /// Represents an OpenAI client for handling chat completions
 #[derive(Debug)]
 pub struct OpenAi {
@@ -14,7 +61,9 @@ pub struct OpenAi {
     base_url: String,
     model: String,
 
-    tools: Vec<ChatCompletionTool>,
+    tools: Vec<ToolDefinition>,
+    capabilities: Capabilities,
+    params: GenerationParams,
     client: Client<OpenAIConfig>,
 }
 
@@ -27,6 +76,8 @@ impl OpenAi {
             model: Default::default(),
 
             tools: Vec::new(),
+            capabilities: Capabilities::default(),
+            params: GenerationParams::default(),
             client: Client::new(),
         }
     }
@@ -51,57 +102,459 @@ impl OpenAi {
             .with_api_key(self.api_key.clone())
             .with_api_base(self.base_url.clone());
 
-        let client = Client::with_config(config);
+        // Retries are handled by the agent, which honors its retry policy and fallbacks
+        let backoff = backoff::ExponentialBackoff {
+            max_elapsed_time: Some(Duration::ZERO),
+            ..Default::default()
+        };
+        let client = Client::with_config(config).with_backoff(backoff);
 
         self.client = client;
 
         self
     }
+
+    /// Probes the optional features supported by the server with minimal requests
+    ///
+    /// The client must be built first.
+    pub async fn probe_capabilities(&self) -> Capabilities {
+        let probe_tool = to_openai_tool(ToolDefinition {
+            name: "probe".to_string(),
+            description: "Capability probe, do not call".to_string(),
+            parameters: json!({"type": "object", "properties": {}}),
+        });
+
+        let tools = self
+            .probe(|args| {
+                args.tools(vec![probe_tool.clone()]);
+            })
+            .await;
+        let json_mode = self
+            .probe(|args| {
+                args.response_format(OpenAiResponseFormat::JsonObject);
+            })
+            .await;
+        let parallel_tool_calls = tools
+            && self
+                .probe(|args| {
+                    args.tools(vec![probe_tool.clone()])
+                        .parallel_tool_calls(true);
+                })
+                .await;
+        let streaming = match self.probe_request(|_| {}) {
+            Some(request) => match self.client.chat().create_stream(request).await {
+                Ok(mut stream) => matches!(stream.next().await, Some(Ok(_))),
+                Err(_) => false,
+            },
+            None => false,
+        };
+
+        let capabilities = Capabilities {
+            tools,
+            json_mode,
+            streaming,
+            parallel_tool_calls,
+        };
+        println!("Probed LLM capabilities: {:?}", capabilities);
+        capabilities
+    }
+
+    /// Sends a probe request with a feature enabled, returns whether it succeeded
+    async fn probe(&self, feature: impl FnOnce(&mut CreateChatCompletionRequestArgs)) -> bool {
+        match self.probe_request(feature) {
+            Some(request) => self.client.chat().create(request).await.is_ok(),
+            None => false,
+        }
+    }
+
+    /// Builds a minimal request with a feature enabled
+    fn probe_request(
+        &self,
+        feature: impl FnOnce(&mut CreateChatCompletionRequestArgs),
+    ) -> Option<CreateChatCompletionRequest> {
+        let message = ChatCompletionRequestUserMessageArgs::default()
+            .content("Reply with an empty json object")
+            .build()
+            .ok()?;
+        let mut args = CreateChatCompletionRequestArgs::default();
+        args.model(&self.model)
+            .max_tokens(1u32)
+            .messages(vec![message.into()]);
+        feature(&mut args);
+        args.build().ok()
+    }
+
+    /// Returns whether tools are described in the prompt instead of sent natively
+    fn uses_prompt_tools(&self) -> bool {
+        !self.capabilities.tools && !self.tools.is_empty()
+    }
+}
+
+impl OpenAi {
+    /// Builds the chat completion request for the conversation
+    fn build_request(
+        &self,
+        conversation: &Conversation,
+        params: &GenerationParams,
+    ) -> Result<CreateChatCompletionRequest> {
+        let mut messages = if self.uses_prompt_tools() {
+            let prompt =
+                tool_prompt::system_prompt(&self.tools, self.capabilities.parallel_tool_calls);
+            tool_prompt::encode_messages(&conversation.messages, prompt)
+        } else {
+            conversation.messages.clone()
+        };
+        let response_format = match &conversation.response_format {
+            ResponseFormat::Text => None,
+            format if !self.capabilities.json_mode => {
+                if let Some(content) = format.instructions() {
+                    messages.push(Message::System { content });
+                }
+                None
+            }
+            ResponseFormat::JsonObject => Some(OpenAiResponseFormat::JsonObject),
+            ResponseFormat::JsonSchema {
+                name,
+                schema,
+                strict,
+            } => Some(OpenAiResponseFormat::JsonSchema {
+                json_schema: ResponseFormatJsonSchema {
+                    description: None,
+                    name: name.clone(),
+                    schema: Some(schema.clone()),
+                    strict: Some(*strict),
+                },
+            }),
+        };
+        let messages = messages
+            .iter()
+            .map(to_openai_message)
+            .collect::<Result<Vec<_>>>()?;
+
+        let mut args = CreateChatCompletionRequestArgs::default();
+        args.max_tokens(conversation.max_tokens)
+            .model(&self.model)
+            .messages(messages);
+        if let Some(response_format) = response_format {
+            args.response_format(response_format);
+        }
+        if self.capabilities.tools && !self.tools.is_empty() {
+            args.tools(
+                self.tools
+                    .iter()
+                    .cloned()
+                    .map(to_openai_tool)
+                    .collect::<Vec<_>>(),
+            );
+            if let Some(tool_choice) = &params.tool_choice {
+                args.tool_choice(to_openai_tool_choice(tool_choice));
+            }
+            if let Some(parallel) = params
+                .parallel_tool_calls
+                .filter(|_| self.capabilities.parallel_tool_calls)
+            {
+                args.parallel_tool_calls(parallel);
+            }
+        }
+
+        if let Some(temperature) = params.temperature {
+            args.temperature(temperature);
+        }
+        if let Some(top_p) = params.top_p {
+            args.top_p(top_p);
+        }
+        if let Some(seed) = params.seed {
+            args.seed(seed);
+        }
+        if let Some(stop) = &params.stop {
+            args.stop(Stop::StringArray(stop.clone()));
+        }
+        if let Some(presence_penalty) = params.presence_penalty {
+            args.presence_penalty(presence_penalty);
+        }
+        if let Some(frequency_penalty) = params.frequency_penalty {
+            args.frequency_penalty(frequency_penalty);
+        }
+        if let Some(user) = &params.user {
+            args.user(user);
+        }
+        Ok(args.build()?)
+    }
+
+    /// Sends the request, with the `extra` fields added to its body if any
+    async fn create(
+        &self,
+        request: CreateChatCompletionRequest,
+        extra: &Map<String, Value>,
+    ) -> Result<CreateChatCompletionResponse> {
+        let response = if extra.is_empty() {
+            self.client.chat().create(request).await?
+        } else {
+            let request = with_extra(&request, extra)?;
+            self.client.chat().create_byot(request).await?
+        };
+        Ok(response)
+    }
+
+    /// Sends the streamed request, with the `extra` fields added to its body if any
+    async fn create_stream(
+        &self,
+        mut request: CreateChatCompletionRequest,
+        extra: &Map<String, Value>,
+    ) -> Result<ChatCompletionResponseStream> {
+        request.stream_options = Some(ChatCompletionStreamOptions {
+            include_usage: true,
+        });
+        let stream = if extra.is_empty() {
+            self.client.chat().create_stream(request).await?
+        } else {
+            request.stream = Some(true);
+            let request = with_extra(&request, extra)?;
+            self.client
+                .chat()
+                .create_stream_byot::<_, CreateChatCompletionStreamResponse>(request)
+                .await?
+        };
+        Ok(stream)
+    }
 }
 
 #[async_trait]
 impl LLM for OpenAi {
     /// Sends a conversation to OpenAI and returns the chat response
-    async fn send(&self, conversation: Conversation) -> ChatResponse {
-        let request = CreateChatCompletionRequestArgs::default()
-            .max_tokens(conversation.max_tokens)
-            .model(&self.model)
-            .messages(conversation.messages.clone())
-            .tools(self.tools.clone())
-            .response_format(ResponseFormat::JsonObject)
-            .build()
-            .unwrap();
+    async fn send(&self, conversation: Conversation) -> Result<ChatResponse> {
+        let params = self.params.merge(&conversation.params);
+        let request = self.build_request(&conversation, &params)?;
 
         let response = self
-            .client
-            .chat()
-            .create(request)
+            .create(request, &params.extra)
             .await
             .inspect_err(|err| {
                 println!("Failed to send CreateChatCompletionRequest: {}", err);
-            })
-            .unwrap();
+            })?;
 
         println!("Sending response {:?}", &response);
 
         let mut resp = ChatResponse::new(conversation.clone());
+        resp.usage = response.usage.map(from_openai_usage);
 
         for choice in response.choices {
             if let Some(content) = &choice.message.content {
                 resp.conversation.append_assistant_content(content.clone());
+                resp.content = Some(content.clone());
             }
 
-            if let Some(tools) = &choice.message.tool_calls {
-                if let Some(exists) = &mut resp.tool_calls {
-                    exists.extend_from_slice(tools);
-                } else {
-                    resp.tool_calls = Some(tools.clone());
-                }
+            if let Some(tools) = choice.message.tool_calls {
+                resp.tool_calls
+                    .get_or_insert_with(Vec::new)
+                    .extend(tools.into_iter().map(from_openai_tool_call));
+            }
+        }
+
+        if self.uses_prompt_tools() {
+            let turn_id = conversation.messages.len();
+            if let Some(calls) = resp
+                .content
+                .as_deref()
+                .and_then(|content| tool_prompt::parse_tool_calls(content, turn_id))
+            {
+                resp.conversation = conversation;
+                resp.content = None;
+                resp.tool_calls = Some(calls);
             }
         }
+        if let Some(calls) = resp
+            .tool_calls
+            .as_mut()
+            .filter(|_| !self.capabilities.parallel_tool_calls)
+        {
+            calls.truncate(1);
+        }
+
+        Ok(resp)
+    }
+
+    /// Sends a conversation to OpenAI and streams the chat response
+    ///
+    /// Falls back to a single response when the server cannot stream, or when tools
+    /// are described in the prompt since their calls are only known once complete.
+    async fn send_stream(&self, conversation: Conversation) -> Result<ChatStream> {
+        if !self.capabilities.streaming || self.uses_prompt_tools() {
+            return Ok(response_stream(self.send(conversation).await?));
+        }
+
+        let params = self.params.merge(&conversation.params);
+        let request = self.build_request(&conversation, &params)?;
+
+        let stream = self
+            .create_stream(request, &params.extra)
+            .await
+            .inspect_err(|err| {
+                println!("Failed to send CreateChatCompletionRequest: {}", err);
+            })?;
+
+        let parallel_tool_calls = self.capabilities.parallel_tool_calls;
+        let deltas = stream.flat_map(move |chunk| {
+            let deltas: Vec<Result<ChatDelta>> = match chunk {
+                Ok(chunk) => chunk
+                    .choices
+                    .into_iter()
+                    .flat_map(|choice| stream_deltas(choice.delta, parallel_tool_calls))
+                    .chain(
+                        chunk
+                            .usage
+                            .map(|usage| ChatDelta::Usage(from_openai_usage(usage))),
+                    )
+                    .map(Ok)
+                    .collect(),
+                Err(err) => vec![Err(err.into())],
+            };
+            stream::iter(deltas)
+        });
+
+        Ok(Box::pin(deltas))
+    }
+}
+
+/// Converts a provider-neutral message into an OpenAI request message
+fn to_openai_message(message: &Message) -> Result<ChatCompletionRequestMessage> {
+    let message = match message {
+        Message::System { content } => ChatCompletionRequestSystemMessageArgs::default()
+            .content(content.clone())
+            .build()?
+            .into(),
+        Message::User { content } => ChatCompletionRequestUserMessageArgs::default()
+            .content(content.clone())
+            .build()?
+            .into(),
+        Message::Assistant {
+            content,
+            tool_calls,
+        } => {
+            let mut args = ChatCompletionRequestAssistantMessageArgs::default();
+            if let Some(content) = content {
+                args.content(content.clone());
+            }
+            if !tool_calls.is_empty() {
+                args.tool_calls(
+                    tool_calls
+                        .iter()
+                        .map(to_openai_tool_call)
+                        .collect::<Vec<_>>(),
+                );
+            }
+            args.build()?.into()
+        }
+        Message::Tool {
+            tool_call_id,
+            content,
+        } => ChatCompletionRequestToolMessageArgs::default()
+            .content(content.clone())
+            .tool_call_id(tool_call_id.clone())
+            .build()?
+            .into(),
+    };
+    Ok(message)
+}
+
+/// Converts a provider-neutral tool call into an OpenAI tool call
+fn to_openai_tool_call(call: &ToolCall) -> ChatCompletionMessageToolCall {
+    ChatCompletionMessageToolCall {
+        id: call.id.clone(),
+        r#type: ChatCompletionToolType::Function,
+        function: FunctionCall {
+            name: call.name.clone(),
+            arguments: call.arguments.clone(),
+        },
+    }
+}
+
+/// Converts an OpenAI tool call into a provider-neutral tool call
+fn from_openai_tool_call(call: ChatCompletionMessageToolCall) -> ToolCall {
+    ToolCall {
+        id: call.id,
+        name: call.function.name,
+        arguments: call.function.arguments,
+    }
+}
+
+/// Converts a provider-neutral tool choice into an OpenAI tool choice
+fn to_openai_tool_choice(tool_choice: &ToolChoice) -> ChatCompletionToolChoiceOption {
+    match tool_choice {
+        ToolChoice::Auto => ChatCompletionToolChoiceOption::Auto,
+        ToolChoice::None => ChatCompletionToolChoiceOption::None,
+        ToolChoice::Required => ChatCompletionToolChoiceOption::Required,
+        ToolChoice::Function(name) => {
+            ChatCompletionToolChoiceOption::Named(ChatCompletionNamedToolChoice {
+                r#type: ChatCompletionToolType::Function,
+                function: FunctionName { name: name.clone() },
+            })
+        }
+    }
+}
+
+/// Serializes the request and adds the provider-specific fields to its body
+fn with_extra(request: &CreateChatCompletionRequest, extra: &Map<String, Value>) -> Result<Value> {
+    let mut body = serde_json::to_value(request).map_err(|err| Error::Llm(err.to_string()))?;
+    if let Some(body) = body.as_object_mut() {
+        body.extend(extra.clone());
+    }
+    Ok(body)
+}
+
+/// Converts a provider-neutral tool definition into an OpenAI tool
+fn to_openai_tool(tool: ToolDefinition) -> ChatCompletionTool {
+    ChatCompletionTool {
+        r#type: ChatCompletionToolType::Function,
+        function: FunctionObject {
+            name: tool.name,
+            description: Some(tool.description),
+            parameters: Some(tool.parameters),
+            strict: None,
+        },
+    }
+}
+
+/// Converts the token usage reported by OpenAI
+fn from_openai_usage(usage: CompletionUsage) -> Usage {
+    Usage {
+        prompt_tokens: usage.prompt_tokens,
+        completion_tokens: usage.completion_tokens,
+        cached_tokens: usage
+            .prompt_tokens_details
+            .and_then(|details| details.cached_tokens)
+            .unwrap_or_default(),
+    }
+}
 
-        resp
+/// Converts the delta of a streamed choice into chat deltas
+///
+/// Only the first tool call is kept when parallel tool calls are not supported, like
+/// in complete responses.
+fn stream_deltas(
+    delta: ChatCompletionStreamResponseDelta,
+    parallel_tool_calls: bool,
+) -> Vec<ChatDelta> {
+    let mut deltas = Vec::new();
+    if let Some(content) = delta.content {
+        deltas.push(ChatDelta::Content(content));
     }
+    for chunk in delta.tool_calls.unwrap_or_default() {
+        if !parallel_tool_calls && chunk.index > 0 {
+            continue;
+        }
+        let (name, arguments) = match chunk.function {
+            Some(function) => (function.name, function.arguments.unwrap_or_default()),
+            None => (None, String::new()),
+        };
+        deltas.push(ChatDelta::ToolCall {
+            index: chunk.index,
+            id: chunk.id,
+            name,
+            arguments,
+        });
+    }
+    deltas
 }
 
 type OpenAiOption = Box<dyn FnOnce(&mut OpenAi)>;
@@ -120,11 +573,59 @@ pub fn with_model(model: &str) -> OpenAiOption {
 
 /// Creates an option to set the base URL
 pub fn with_base_url(base_url: &str) -> OpenAiOption {
-    let base_url = base_url.trim_matches('/').to_string();
+    let base_url = base_url.trim_end_matches('/').to_string();
     Box::new(move |openai| openai.base_url = base_url)
 }
 
 /// Creates an option to set the tools for chat completion
-pub fn with_tools(tools: Vec<ChatCompletionTool>) -> OpenAiOption {
+pub fn with_tools(tools: Vec<ToolDefinition>) -> OpenAiOption {
     Box::new(move |openai| openai.tools = tools)
 }
+
+/// Creates an option to set the default generation parameters
+pub fn with_params(params: GenerationParams) -> OpenAiOption {
+    Box::new(move |openai| openai.params = params)
+}
+
+/// Creates an option to set the features supported by the server
+pub fn with_capabilities(capabilities: Capabilities) -> OpenAiOption {
+    Box::new(move |openai| openai.capabilities = capabilities)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn base_url_keeps_its_leading_slash() {
+        let mut openai = OpenAi::new();
+        openai.with_option(with_base_url("/proxy/v1/"));
+        assert_eq!(openai.base_url, "/proxy/v1");
+    }
+
+    #[test]
+    fn keeps_the_first_streamed_tool_call_without_parallel_tool_calls() {
+        let delta = || -> ChatCompletionStreamResponseDelta {
+            serde_json::from_value(json!({
+                "tool_calls": [
+                    {"index": 0, "id": "call_0", "function": {"name": "search", "arguments": ""}},
+                    {"index": 1, "id": "call_1", "function": {"name": "fetch", "arguments": ""}}
+                ]
+            }))
+            .unwrap()
+        };
+
+        let indexes = |deltas: Vec<ChatDelta>| -> Vec<u32> {
+            deltas
+                .into_iter()
+                .filter_map(|delta| match delta {
+                    ChatDelta::ToolCall { index, .. } => Some(index),
+                    _ => None,
+                })
+                .collect()
+        };
+        assert_eq!(indexes(stream_deltas(delta(), true)), [0, 1]);
+        assert_eq!(indexes(stream_deltas(delta(), false)), [0]);
+    }
+}
@@ -0,0 +1,115 @@
+use serde::{Deserialize, Serialize};
+use serde_json::{Map, Value};
+
+/// Sampling and request parameters sent to the LLM
+///
+/// Every field is optional, unset fields are left to the provider defaults.
+/// Parameters a provider does not support are ignored.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct GenerationParams {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub temperature: Option<f32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub top_p: Option<f32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub seed: Option<i64>,
+    /// Sequences where the LLM stops generating
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub stop: Option<Vec<String>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub tool_choice: Option<ToolChoice>,
+    /// Whether the LLM may call several tools in a single response
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub parallel_tool_calls: Option<bool>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub presence_penalty: Option<f32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub frequency_penalty: Option<f32>,
+    /// Identifier of the end user, for abuse monitoring
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub user: Option<String>,
+    /// Provider-specific fields added as is to the request body
+    #[serde(skip_serializing_if = "Map::is_empty")]
+    pub extra: Map<String, Value>,
+}
+
+/// Controls whether and which tool the LLM calls
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ToolChoice {
+    /// The LLM decides
+    Auto,
+    /// The LLM does not call any tool
+    None,
+    /// The LLM calls at least one tool
+    Required,
+    /// The LLM calls the named tool, e.g. `tool_choice = { function = "search" }`
+    Function(String),
+}
+
+impl GenerationParams {
+    /// Returns these parameters overridden by the fields set in `overrides`
+    ///
+    /// `extra` fields are merged, the ones of `overrides` win.
+    pub fn merge(&self, overrides: &GenerationParams) -> GenerationParams {
+        let mut extra = self.extra.clone();
+        extra.extend(overrides.extra.clone());
+
+        GenerationParams {
+            temperature: overrides.temperature.or(self.temperature),
+            top_p: overrides.top_p.or(self.top_p),
+            seed: overrides.seed.or(self.seed),
+            stop: overrides.stop.clone().or_else(|| self.stop.clone()),
+            tool_choice: overrides
+                .tool_choice
+                .clone()
+                .or_else(|| self.tool_choice.clone()),
+            parallel_tool_calls: overrides.parallel_tool_calls.or(self.parallel_tool_calls),
+            presence_penalty: overrides.presence_penalty.or(self.presence_penalty),
+            frequency_penalty: overrides.frequency_penalty.or(self.frequency_penalty),
+            user: overrides.user.clone().or_else(|| self.user.clone()),
+            extra,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn overrides_win_and_extra_is_merged() {
+        let base: GenerationParams = toml::from_str(
+            r#"
+            temperature = 0.2
+            seed = 7
+            tool_choice = "auto"
+
+            [extra]
+            num_ctx = 8192
+            keep_alive = "5m"
+            "#,
+        )
+        .unwrap();
+        let overrides = GenerationParams {
+            temperature: Some(0.9),
+            tool_choice: Some(ToolChoice::Function("search".to_string())),
+            extra: json!({"keep_alive": "1m"}).as_object().unwrap().clone(),
+            ..Default::default()
+        };
+
+        let merged = base.merge(&overrides);
+        assert_eq!(merged.temperature, Some(0.9));
+        assert_eq!(merged.seed, Some(7));
+        assert_eq!(
+            merged.tool_choice,
+            Some(ToolChoice::Function("search".to_string()))
+        );
+        assert_eq!(
+            Value::Object(merged.extra),
+            json!({"num_ctx": 8192, "keep_alive": "1m"})
+        );
+    }
+}
@@ -0,0 +1,288 @@
+//! Prompt templates read from the `[prompts.<name>]` and `[partials]` tables
+//!
+//! Templates reference variables with `{{name}}` and partials with `{{> name}}`,
+//! partials can themselves reference variables and other partials.
+
+use crate::error::{Error, Result};
+use crate::llm::message::ResponseFormat;
+use serde::Deserialize;
+use std::collections::{BTreeSet, HashMap};
+use std::fs;
+
+/// Nesting depth of partials, deeper references are reported as cycles
+const MAX_PARTIAL_DEPTH: usize = 8;
+
+/// A prompt template of the `[prompts.<name>]` table
+///
+/// Each prompt is given inline or read from a file, relative to the working directory.
+#[derive(Debug, Deserialize, Clone, Default, PartialEq)]
+pub struct PromptConfig {
+    pub system: Option<String>,
+    pub system_file: Option<String>,
+    pub user: Option<String>,
+    pub user_file: Option<String>,
+    /// Maximum tokens of the conversations built from the template
+    pub max_tokens: Option<u32>,
+    /// Format of the responses, e.g. `response_format = { type = "json_object" }`
+    pub response_format: Option<ResponseFormat>,
+}
+
+/// The parsed prompt templates of the configuration
+#[derive(Debug, Clone, Default)]
+pub struct PromptTemplates {
+    prompts: HashMap<String, Prompt>,
+    partials: HashMap<String, Template>,
+}
+
+/// A parsed prompt template
+#[derive(Debug, Clone)]
+pub struct Prompt {
+    system: Option<Template>,
+    user: Option<Template>,
+    pub max_tokens: Option<u32>,
+    pub response_format: Option<ResponseFormat>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+struct Template(Vec<Segment>);
+
+#[derive(Debug, Clone, PartialEq)]
+enum Segment {
+    Text(String),
+    Variable(String),
+    Partial(String),
+}
+
+impl PromptTemplates {
+    /// Reads and parses the templates, checking that the partials they use exist
+    pub fn from_config(
+        prompts: &HashMap<String, PromptConfig>,
+        partials: &HashMap<String, String>,
+    ) -> Result<Self> {
+        let partials = partials
+            .iter()
+            .map(|(name, text)| {
+                let template = Template::parse(text)
+                    .map_err(|err| Error::Config(format!("partial {}: {}", name, err)))?;
+                Ok((name.clone(), template))
+            })
+            .collect::<Result<HashMap<_, _>>>()?;
+
+        let mut templates = PromptTemplates {
+            prompts: HashMap::new(),
+            partials,
+        };
+        for (name, config) in prompts {
+            let load = |inline: &Option<String>, file: &Option<String>, part: &str| {
+                let text = match (inline, file) {
+                    (Some(_), Some(_)) => {
+                        return Err(format!("both {} and {}_file are set", part, part));
+                    }
+                    (Some(text), None) => text.clone(),
+                    (None, Some(path)) => fs::read_to_string(path)
+                        .map_err(|err| format!("could not read {}: {}", path, err))?,
+                    (None, None) => return Ok(None),
+                };
+                let template = Template::parse(&text)?;
+                templates.collect_variables(&template, 0, &mut BTreeSet::new())?;
+                Ok(Some(template))
+            };
+            let prompt = Prompt {
+                system: load(&config.system, &config.system_file, "system")
+                    .map_err(|err| Error::Config(format!("prompt {}: {}", name, err)))?,
+                user: load(&config.user, &config.user_file, "user")
+                    .map_err(|err| Error::Config(format!("prompt {}: {}", name, err)))?,
+                max_tokens: config.max_tokens,
+                response_format: config.response_format.clone(),
+            };
+            templates.prompts.insert(name.clone(), prompt);
+        }
+        Ok(templates)
+    }
+
+    /// Returns the prompt template with the name
+    pub fn get(&self, name: &str) -> Result<&Prompt> {
+        self.prompts
+            .get(name)
+            .ok_or_else(|| Error::Template(format!("prompt {} is not defined", name)))
+    }
+
+    /// Returns the variables used by the prompt, including the ones of its partials
+    pub fn variables(&self, name: &str) -> Result<BTreeSet<String>> {
+        let prompt = self.get(name)?;
+        let mut variables = BTreeSet::new();
+        for template in [&prompt.system, &prompt.user].into_iter().flatten() {
+            self.collect_variables(template, 0, &mut variables)
+                .map_err(Error::Template)?;
+        }
+        Ok(variables)
+    }
+
+    /// Renders the system and user prompts of the template
+    ///
+    /// Fails when a variable used by the template is not supplied.
+    pub fn render(
+        &self,
+        name: &str,
+        vars: &[(&str, &str)],
+    ) -> Result<(Option<String>, Option<String>)> {
+        let missing: Vec<String> = self
+            .variables(name)?
+            .into_iter()
+            .filter(|variable| vars.iter().all(|(name, _)| name != variable))
+            .collect();
+        if !missing.is_empty() {
+            return Err(Error::Template(format!(
+                "prompt {} is missing variables: {}",
+                name,
+                missing.join(", ")
+            )));
+        }
+
+        let prompt = self.get(name)?;
+        let render = |template: &Option<Template>| {
+            template.as_ref().map(|template| {
+                let mut text = String::new();
+                self.render_into(template, vars, &mut text);
+                text
+            })
+        };
+        Ok((render(&prompt.system), render(&prompt.user)))
+    }
+
+    /// Collects the variables of the template, failing on unknown or cyclic partials
+    fn collect_variables(
+        &self,
+        template: &Template,
+        depth: usize,
+        variables: &mut BTreeSet<String>,
+    ) -> std::result::Result<(), String> {
+        if depth > MAX_PARTIAL_DEPTH {
+            return Err("partials are nested too deeply or reference themselves".to_string());
+        }
+        for segment in &template.0 {
+            match segment {
+                Segment::Text(_) => {}
+                Segment::Variable(name) => {
+                    variables.insert(name.clone());
+                }
+                Segment::Partial(name) => {
+                    let partial = self
+                        .partials
+                        .get(name)
+                        .ok_or_else(|| format!("partial {} is not defined", name))?;
+                    self.collect_variables(partial, depth + 1, variables)?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Renders a template whose partials and variables were checked
+    fn render_into(&self, template: &Template, vars: &[(&str, &str)], text: &mut String) {
+        for segment in &template.0 {
+            match segment {
+                Segment::Text(part) => text.push_str(part),
+                Segment::Variable(name) => {
+                    if let Some((_, value)) = vars.iter().find(|(var, _)| var == name) {
+                        text.push_str(value);
+                    }
+                }
+                Segment::Partial(name) => {
+                    if let Some(partial) = self.partials.get(name) {
+                        self.render_into(partial, vars, text);
+                    }
+                }
+            }
+        }
+    }
+}
+
+impl Template {
+    /// Parses `{{variable}}` and `{{> partial}}` references, names are made of
+    /// letters, digits, `_`, `-` and `.`
+    fn parse(text: &str) -> std::result::Result<Template, String> {
+        let mut segments = Vec::new();
+        let mut rest = text;
+        while let Some(start) = rest.find("{{") {
+            if start > 0 {
+                segments.push(Segment::Text(rest[..start].to_string()));
+            }
+            let end = rest[start..]
+                .find("}}")
+                .ok_or_else(|| "unclosed {{".to_string())?;
+            let tag = rest[start + 2..start + end].trim();
+            let (partial, name) = match tag.strip_prefix('>') {
+                Some(name) => (true, name.trim()),
+                None => (false, tag),
+            };
+            let valid = !name.is_empty()
+                && name
+                    .chars()
+                    .all(|c| c.is_alphanumeric() || matches!(c, '_' | '-' | '.'));
+            if !valid {
+                return Err(format!("invalid reference {{{{{}}}}}", tag));
+            }
+            segments.push(if partial {
+                Segment::Partial(name.to_string())
+            } else {
+                Segment::Variable(name.to_string())
+            });
+            rest = &rest[start + end + 2..];
+        }
+        if !rest.is_empty() {
+            segments.push(Segment::Text(rest.to_string()));
+        }
+        Ok(Template(segments))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn renders_variables_and_partials() {
+        let config: toml::Table = toml::from_str(
+            r#"
+            [partials]
+            format = '{"data": [{"price": "{{currency}} price"}]}'
+            loop = "{{> loop}}"
+
+            [prompts.search]
+            system = "You look up prices."
+            user = "Price in {{ province }}? Reply with: {{> format}}"
+
+            [prompts.broken]
+            user = "{{> loop}}"
+            "#,
+        )
+        .unwrap();
+        let prompts: HashMap<String, PromptConfig> = config["prompts"].clone().try_into().unwrap();
+        let partials: HashMap<String, String> = config["partials"].clone().try_into().unwrap();
+        assert!(PromptTemplates::from_config(&prompts, &partials).is_err());
+
+        let prompts = HashMap::from([("search".to_string(), prompts["search"].clone())]);
+        let templates = PromptTemplates::from_config(&prompts, &partials).unwrap();
+        assert_eq!(
+            templates.variables("search").unwrap(),
+            BTreeSet::from(["currency".to_string(), "province".to_string()])
+        );
+        assert_eq!(
+            templates
+                .render("search", &[("province", "Hubei"), ("currency", "CNY")])
+                .unwrap(),
+            (
+                Some("You look up prices.".to_string()),
+                Some(
+                    r#"Price in Hubei? Reply with: {"data": [{"price": "CNY price"}]}"#.to_string()
+                )
+            )
+        );
+        assert!(
+            templates
+                .render("search", &[("province", "Hubei")])
+                .is_err()
+        );
+    }
+}
@@ -0,0 +1,277 @@
+//! Token counting of conversations, to enforce budgets before sending them
+
+use crate::error::{Error, Result};
+use crate::llm::message::{Message, ToolDefinition};
+use base64::Engine;
+use base64::engine::general_purpose::STANDARD;
+use serde::Deserialize;
+use std::fs;
+use std::sync::Arc;
+use tiktoken_rs::CoreBPE;
+
+/// Tokens wrapping each message in the chat format
+const MESSAGE_OVERHEAD_TOKENS: usize = 3;
+/// Tokens priming the reply of the assistant
+const REPLY_PRIMING_TOKENS: usize = 3;
+/// Tokens wrapping the tool definitions, added once when there is any tool
+const TOOLS_OVERHEAD_TOKENS: usize = 12;
+/// Tokens wrapping each tool definition
+const TOOL_OVERHEAD_TOKENS: usize = 8;
+const DEFAULT_BYTES_PER_TOKEN: f64 = 4.0;
+
+const ENDOFTEXT: &str = "<|endoftext|>";
+const ENDOFPROMPT: &str = "<|endofprompt|>";
+const CL100K_BASE_PATTERN: &str = r"(?i:'s|'t|'re|'ve|'m|'ll|'d)|[^\r\n\p{L}\p{N}]?\p{L}+|\p{N}{1,3}| ?[^\s\p{L}\p{N}]+[\r\n]*|\s*[\r\n]+|\s+(?!\S)|\s+";
+const O200K_BASE_PATTERN: &str = concat!(
+    r"[^\r\n\p{L}\p{N}]?[\p{Lu}\p{Lt}\p{Lm}\p{Lo}\p{M}]*[\p{Ll}\p{Lm}\p{Lo}\p{M}]+(?i:'s|'t|'re|'ve|'m|'ll|'d)?",
+    r"|[^\r\n\p{L}\p{N}]?[\p{Lu}\p{Lt}\p{Lm}\p{Lo}\p{M}]+[\p{Ll}\p{Lm}\p{Lo}\p{M}]*(?i:'s|'t|'re|'ve|'m|'ll|'d)?",
+    r"|\p{N}{1,3}| ?[^\s\p{L}\p{N}]+[\r\n/]*|\s*[\r\n]+|\s+(?!\S)|\s+",
+);
+
+/// Counts the tokens of the text sent to an LLM
+///
+/// The counts of messages and tools approximate the format of the Chat Completions
+/// API, the exact overhead depends on the provider and the model.
+pub trait TokenCounter: Send + Sync {
+    /// Returns the number of tokens of the text
+    fn count(&self, text: &str) -> usize;
+
+    /// Returns the tokens of a message, including the tokens wrapping it
+    fn count_message(&self, message: &Message) -> usize {
+        let tokens = match message {
+            Message::System { content } | Message::User { content } => self.count(content),
+            Message::Assistant {
+                content,
+                tool_calls,
+            } => {
+                content
+                    .as_deref()
+                    .map(|text| self.count(text))
+                    .unwrap_or_default()
+                    + tool_calls
+                        .iter()
+                        .map(|call| {
+                            MESSAGE_OVERHEAD_TOKENS
+                                + self.count(&call.name)
+                                + self.count(&call.arguments)
+                        })
+                        .sum::<usize>()
+            }
+            Message::Tool {
+                tool_call_id,
+                content,
+            } => self.count(tool_call_id) + self.count(content),
+        };
+        MESSAGE_OVERHEAD_TOKENS + tokens
+    }
+
+    /// Returns the tokens of the messages, including the priming of the reply
+    fn count_messages(&self, messages: &[Message]) -> usize {
+        REPLY_PRIMING_TOKENS
+            + messages
+                .iter()
+                .map(|message| self.count_message(message))
+                .sum::<usize>()
+    }
+
+    /// Returns the tokens the tool definitions add to every request
+    fn count_tools(&self, tools: &[ToolDefinition]) -> usize {
+        if tools.is_empty() {
+            return 0;
+        }
+        TOOLS_OVERHEAD_TOKENS
+            + tools
+                .iter()
+                .map(|tool| {
+                    TOOL_OVERHEAD_TOKENS
+                        + self.count(&tool.name)
+                        + self.count(&tool.description)
+                        + self.count(&tool.parameters.to_string())
+                })
+                .sum::<usize>()
+    }
+}
+
+/// A tokenizer, read from the `tokenizer` setting of an LLM profile
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Tokenizer {
+    /// BPE of GPT-4o, GPT-4.1 and the o-series models
+    O200kBase,
+    /// BPE of GPT-4 and GPT-3.5
+    Cl100kBase,
+    /// See [`HeuristicTokenCounter`]
+    Heuristic,
+}
+
+impl Tokenizer {
+    /// Returns the tokenizer of an OpenAI model, `None` for unknown models
+    pub fn for_model(model: &str) -> Option<Tokenizer> {
+        match tiktoken_rs::tokenizer::get_tokenizer(model)? {
+            tiktoken_rs::tokenizer::Tokenizer::O200kBase => Some(Tokenizer::O200kBase),
+            tiktoken_rs::tokenizer::Tokenizer::Cl100kBase => Some(Tokenizer::Cl100kBase),
+            _ => None,
+        }
+    }
+}
+
+/// Returns the token counter of a model
+///
+/// The tokenizer is detected from the model name when unset, models of other
+/// providers fall back to [`HeuristicTokenCounter`]. BPE vocabularies are bundled,
+/// `vocab_file` loads one from disk instead, in the `.tiktoken` format.
+pub fn token_counter(
+    tokenizer: Option<Tokenizer>,
+    vocab_file: Option<&str>,
+    model: &str,
+) -> Result<Arc<dyn TokenCounter>> {
+    let tokenizer = tokenizer
+        .or_else(|| Tokenizer::for_model(model))
+        .unwrap_or(Tokenizer::Heuristic);
+    let counter: Arc<dyn TokenCounter> = match (tokenizer, vocab_file) {
+        (Tokenizer::Heuristic, _) => Arc::new(HeuristicTokenCounter::default()),
+        (tokenizer, Some(path)) => Arc::new(BpeTokenCounter::from_file(tokenizer, path)?),
+        (tokenizer, None) => Arc::new(BpeTokenCounter::bundled(tokenizer)),
+    };
+    Ok(counter)
+}
+
+/// Counts tokens with the byte pair encoding of OpenAI models, like tiktoken
+pub struct BpeTokenCounter {
+    bpe: Bpe,
+}
+
+enum Bpe {
+    Bundled(&'static CoreBPE),
+    Loaded(Box<CoreBPE>),
+}
+
+impl BpeTokenCounter {
+    /// Creates a counter with a bundled vocabulary, loaded once per process
+    ///
+    /// [`Tokenizer::Heuristic`] is counted with `o200k_base`.
+    pub fn bundled(tokenizer: Tokenizer) -> Self {
+        let bpe = match tokenizer {
+            Tokenizer::Cl100kBase => tiktoken_rs::cl100k_base_singleton(),
+            Tokenizer::O200kBase | Tokenizer::Heuristic => tiktoken_rs::o200k_base_singleton(),
+        };
+        Self {
+            bpe: Bpe::Bundled(bpe),
+        }
+    }
+
+    /// Creates a counter with the vocabulary of a `.tiktoken` file, whose lines are
+    /// a base64 token followed by its rank
+    pub fn from_file(tokenizer: Tokenizer, path: &str) -> Result<Self> {
+        let content = fs::read_to_string(path).map_err(|err| {
+            Error::Config(format!("could not read vocabulary file {}: {}", path, err))
+        })?;
+        let invalid = |line: usize| {
+            Error::Config(format!(
+                "invalid vocabulary file {} at line {}",
+                path,
+                line + 1
+            ))
+        };
+
+        let encoder = content
+            .lines()
+            .enumerate()
+            .filter(|(_, line)| !line.trim().is_empty())
+            .map(|(index, line)| {
+                let (token, rank) = line.split_once(' ').ok_or_else(|| invalid(index))?;
+                let token = STANDARD.decode(token).map_err(|_| invalid(index))?;
+                let rank = rank.trim().parse().map_err(|_| invalid(index))?;
+                Ok((token, rank))
+            })
+            .collect::<Result<_>>()?;
+        let (pattern, special_tokens) = match tokenizer {
+            Tokenizer::Cl100kBase => (
+                CL100K_BASE_PATTERN,
+                [
+                    (ENDOFTEXT.to_string(), 100257),
+                    (ENDOFPROMPT.to_string(), 100276),
+                ],
+            ),
+            Tokenizer::O200kBase | Tokenizer::Heuristic => (
+                O200K_BASE_PATTERN,
+                [
+                    (ENDOFTEXT.to_string(), 199999),
+                    (ENDOFPROMPT.to_string(), 200018),
+                ],
+            ),
+        };
+
+        let bpe = CoreBPE::new(encoder, special_tokens.into_iter().collect(), pattern)
+            .map_err(|err| Error::Config(format!("invalid vocabulary file {}: {}", path, err)))?;
+        Ok(Self {
+            bpe: Bpe::Loaded(Box::new(bpe)),
+        })
+    }
+
+    fn bpe(&self) -> &CoreBPE {
+        match &self.bpe {
+            Bpe::Bundled(bpe) => bpe,
+            Bpe::Loaded(bpe) => bpe,
+        }
+    }
+}
+
+impl TokenCounter for BpeTokenCounter {
+    fn count(&self, text: &str) -> usize {
+        self.bpe().encode_ordinary(text).len()
+    }
+}
+
+/// Estimates tokens from the UTF-8 length of the text, for models without a
+/// known tokenizer
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct HeuristicTokenCounter {
+    pub bytes_per_token: f64,
+}
+
+impl Default for HeuristicTokenCounter {
+    fn default() -> Self {
+        Self {
+            bytes_per_token: DEFAULT_BYTES_PER_TOKEN,
+        }
+    }
+}
+
+impl TokenCounter for HeuristicTokenCounter {
+    fn count(&self, text: &str) -> usize {
+        (text.len() as f64 / self.bytes_per_token).ceil() as usize
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::llm::message::Conversation;
+    use serde_json::json;
+
+    #[test]
+    fn counts_conversations_with_their_tools() {
+        let counter = token_counter(None, None, "gpt-4o").unwrap();
+        assert_eq!(counter.count("hello world"), 2);
+
+        let mut conversation = Conversation::new_with_prompt(256, "Be brief".to_string());
+        conversation.append_user_content("hello world".to_string());
+        let tools = vec![ToolDefinition {
+            name: "search".to_string(),
+            description: "Search the web".to_string(),
+            parameters: json!({"type": "object"}),
+        }];
+
+        let counts = conversation.message_token_counts(counter.as_ref());
+        assert_eq!(counts, [5, 5]);
+        assert_eq!(
+            conversation.token_count(counter.as_ref(), &tools),
+            5 + 5 + REPLY_PRIMING_TOKENS + counter.count_tools(&tools)
+        );
+        assert!(counter.count_tools(&tools) > TOOLS_OVERHEAD_TOKENS + TOOL_OVERHEAD_TOKENS);
+
+        let heuristic = token_counter(None, None, "claude-sonnet-4").unwrap();
+        assert_eq!(heuristic.count("hello world"), 3);
+    }
+}
@@ -0,0 +1,191 @@
+//! Prompt-based tool calling for models without native tool support
+//!
+//! The tools are described in the system prompt and the model is asked to answer
+//! with a JSON object listing the calls. Tool calls and results of the conversation
+//! are rewritten as plain assistant and user messages.
+
+use crate::llm::message::{Message, ToolCall, ToolDefinition};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+/// Builds the system prompt describing the tools and the expected reply format
+pub fn system_prompt(tools: &[ToolDefinition], parallel_tool_calls: bool) -> String {
+    let mut prompt = String::from("You can call the following tools:\n");
+    for tool in tools {
+        prompt.push_str(&format!(
+            "- {}: {}\n  arguments JSON schema: {}\n",
+            tool.name, tool.description, tool.parameters
+        ));
+    }
+    let calls = if parallel_tool_calls {
+        "one or more tool calls"
+    } else {
+        "exactly one tool call"
+    };
+    prompt.push_str(&format!(
+        "\nTo call tools, reply with only a JSON object with {} and nothing else:\n\
+         {{\"tool_calls\": [{{\"name\": \"<tool name>\", \"arguments\": {{...}}}}]}}\n\
+         Tool results are sent back to you in the next user message. \
+         When you have the final answer, reply with plain text.",
+        calls
+    ));
+    prompt
+}
+
+/// Rewrites the messages so that they carry no native tool calls or tool results
+///
+/// The tools prompt is appended to the first system message, or inserted first.
+pub fn encode_messages(messages: &[Message], tools_prompt: String) -> Vec<Message> {
+    let mut encoded = Vec::with_capacity(messages.len() + 1);
+    let mut tools_prompt = Some(tools_prompt);
+
+    for message in messages {
+        let message = match message {
+            Message::System { content } => match tools_prompt.take() {
+                Some(prompt) => Message::System {
+                    content: format!("{}\n\n{}", content, prompt),
+                },
+                None => message.clone(),
+            },
+            Message::Assistant {
+                content,
+                tool_calls,
+            } if !tool_calls.is_empty() => {
+                let calls = encode_tool_calls(tool_calls);
+                let content = match content.as_deref().filter(|text| !text.is_empty()) {
+                    Some(text) => format!("{}\n{}", text, calls),
+                    None => calls,
+                };
+                Message::Assistant {
+                    content: Some(content),
+                    tool_calls: Vec::new(),
+                }
+            }
+            Message::Tool {
+                tool_call_id,
+                content,
+            } => Message::User {
+                content: format!("Result of tool call {}:\n{}", tool_call_id, content),
+            },
+            _ => message.clone(),
+        };
+        encoded.push(message);
+    }
+
+    if let Some(prompt) = tools_prompt {
+        encoded.insert(0, Message::System { content: prompt });
+    }
+    encoded
+}
+
+/// Extracts the tool calls of a reply written in the format of [`system_prompt`]
+///
+/// Returns `None` when the reply is a plain answer. Call ids are generated from
+/// `turn_id` since the model does not produce any.
+pub fn parse_tool_calls(content: &str, turn_id: usize) -> Option<Vec<ToolCall>> {
+    let json = strip_code_fence(content.trim());
+    let reply: PromptReply = serde_json::from_str(json).ok()?;
+    if reply.tool_calls.is_empty() {
+        return None;
+    }
+
+    let calls = reply
+        .tool_calls
+        .into_iter()
+        .enumerate()
+        .map(|(index, call)| ToolCall {
+            id: format!("call_{}_{}", turn_id, index),
+            name: call.name,
+            arguments: match call.arguments {
+                Value::String(arguments) => arguments,
+                arguments => arguments.to_string(),
+            },
+        })
+        .collect();
+    Some(calls)
+}
+
+#[derive(Serialize, Deserialize)]
+struct PromptReply {
+    tool_calls: Vec<PromptToolCall>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct PromptToolCall {
+    name: String,
+    #[serde(default)]
+    arguments: Value,
+}
+
+/// Serializes tool calls the way the model is asked to write them
+fn encode_tool_calls(tool_calls: &[ToolCall]) -> String {
+    let reply = PromptReply {
+        tool_calls: tool_calls
+            .iter()
+            .map(|call| PromptToolCall {
+                name: call.name.clone(),
+                arguments: serde_json::from_str(&call.arguments)
+                    .unwrap_or_else(|_| Value::String(call.arguments.clone())),
+            })
+            .collect(),
+    };
+    serde_json::to_string(&reply).unwrap_or_default()
+}
+
+/// Removes a surrounding markdown code fence, e.g. ```json ... ```
+pub(crate) fn strip_code_fence(content: &str) -> &str {
+    content
+        .strip_prefix("```")
+        .and_then(|rest| rest.strip_suffix("```"))
+        .map(|rest| rest.trim_start_matches("json").trim())
+        .unwrap_or(content)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn round_trips_tool_calls_through_text() {
+        let calls = vec![ToolCall {
+            id: "call_0_0".to_string(),
+            name: "search".to_string(),
+            arguments: r#"{"query":"rust"}"#.to_string(),
+        }];
+        let messages = vec![
+            Message::User {
+                content: "find rust".to_string(),
+            },
+            Message::Assistant {
+                content: None,
+                tool_calls: calls.clone(),
+            },
+            Message::Tool {
+                tool_call_id: "call_0_0".to_string(),
+                content: "found".to_string(),
+            },
+        ];
+
+        let encoded = encode_messages(&messages, "tools".to_string());
+        assert_eq!(
+            encoded[0],
+            Message::System {
+                content: "tools".to_string()
+            }
+        );
+        let Message::Assistant {
+            content: Some(content),
+            tool_calls,
+        } = &encoded[2]
+        else {
+            panic!("expected an assistant message");
+        };
+        assert!(tool_calls.is_empty());
+        assert_eq!(parse_tool_calls(content, 0), Some(calls));
+        assert!(matches!(encoded[3], Message::User { .. }));
+
+        let fenced = "```json\n{\"tool_calls\": [{\"name\": \"search\", \"arguments\": {}}]}\n```";
+        assert_eq!(parse_tool_calls(fenced, 1).unwrap()[0].id, "call_1_0");
+        assert_eq!(parse_tool_calls("The answer is 42", 2), None);
+    }
+}
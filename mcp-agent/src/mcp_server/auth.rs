@@ -0,0 +1,336 @@
+use crate::error::{Error, Result};
+use async_trait::async_trait;
+use axum::extract::{Request, State};
+use axum::http::request::Parts;
+use axum::http::{HeaderMap, StatusCode, header};
+use axum::middleware::Next;
+use axum::response::{IntoResponse, Response};
+use jsonwebtoken::{Algorithm, DecodingKey, Validation};
+use rmcp::RoleServer;
+use rmcp::service::RequestContext;
+use serde::Deserialize;
+use serde_json::{Map, Value};
+use std::collections::HashMap;
+use std::fs;
+use std::sync::Arc;
+
+const API_KEY_HEADER: &str = "x-api-key";
+/// Authentication scheme of the `Authorization` header, case-insensitive
+const BEARER_SCHEME: &str = "Bearer";
+const DEFAULT_JWT_LEEWAY_SECS: u64 = 60;
+
+/// Authentication of the HTTP transports, read from the `[server.auth]` table
+///
+/// Requests are accepted without credentials when no API key, JWT verification or
+/// token validator is set.
+#[derive(Debug, Deserialize, Clone, Default, PartialEq)]
+#[serde(default)]
+pub struct AuthConfig {
+    /// API keys by client name, sent as `Authorization: Bearer <key>` or `X-API-Key: <key>`
+    pub api_keys: HashMap<String, String>,
+    /// Verification of JWT bearer tokens, read from the `[server.auth.jwt]` table
+    pub jwt: Option<JwtConfig>,
+}
+
+/// Verification of JWT bearer tokens signed with a local key
+#[derive(Debug, Deserialize, Clone, PartialEq)]
+pub struct JwtConfig {
+    pub algorithm: JwtAlgorithm,
+    /// Shared secret of HS256
+    pub secret: Option<String>,
+    /// PEM file of the RSA public key of RS256
+    pub public_key_file: Option<String>,
+    /// Expected `iss` claim
+    pub issuer: Option<String>,
+    /// Expected `aud` claim
+    pub audience: Option<String>,
+    /// Clock skew tolerated on `exp` and `nbf`
+    #[serde(default = "default_jwt_leeway_secs")]
+    pub leeway_secs: u64,
+}
+
+fn default_jwt_leeway_secs() -> u64 {
+    DEFAULT_JWT_LEEWAY_SECS
+}
+
+/// Signing algorithms of the JWT bearer tokens
+#[derive(Debug, Deserialize, Clone, Copy, PartialEq, Eq)]
+pub enum JwtAlgorithm {
+    HS256,
+    RS256,
+}
+
+/// How a principal was authenticated
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AuthMethod {
+    ApiKey,
+    Jwt,
+    /// Accepted by the [`TokenValidator`] of the server
+    Token,
+}
+
+/// The authenticated caller of a request
+#[derive(Debug, Clone, PartialEq)]
+pub struct Principal {
+    /// Name of the API key, or `sub` claim of the token
+    pub id: String,
+    pub method: AuthMethod,
+    /// Claims of the token, empty for API keys
+    pub claims: Map<String, Value>,
+}
+
+impl Principal {
+    /// Creates a principal without claims
+    pub fn new(id: impl Into<String>, method: AuthMethod) -> Self {
+        Self {
+            id: id.into(),
+            method,
+            claims: Map::new(),
+        }
+    }
+}
+
+/// Checks the bearer tokens the API keys and JWT verification do not accept, e.g.
+/// against an identity provider
+#[async_trait]
+pub trait TokenValidator: Send + Sync {
+    /// Returns the principal of the token, `None` when the token is not valid
+    async fn validate(&self, token: &str) -> Result<Option<Principal>>;
+}
+
+/// Returns the principal of the request, set when the server authenticates requests
+///
+/// The SSE and streamable HTTP transports store the request parts in the extensions
+/// of the MCP requests, the principal is read from the extensions of these parts.
+pub fn principal(context: &RequestContext<RoleServer>) -> Option<Principal> {
+    context
+        .extensions
+        .get::<Parts>()?
+        .extensions
+        .get::<Principal>()
+        .cloned()
+}
+
+/// Authenticates the requests of the HTTP transports
+pub struct Authenticator {
+    api_keys: Vec<(String, String)>,
+    jwt: Option<(DecodingKey, Validation)>,
+    validator: Option<Arc<dyn TokenValidator>>,
+}
+
+impl Authenticator {
+    /// Creates the authenticator of the configuration, `None` when nothing is to check
+    pub fn new(
+        config: &AuthConfig,
+        validator: Option<Arc<dyn TokenValidator>>,
+    ) -> Result<Option<Self>> {
+        if config.api_keys.is_empty() && config.jwt.is_none() && validator.is_none() {
+            return Ok(None);
+        }
+
+        let api_keys = config
+            .api_keys
+            .iter()
+            .map(|(name, key)| (name.clone(), key.clone()))
+            .collect();
+        let jwt = config.jwt.as_ref().map(JwtConfig::decoding).transpose()?;
+        Ok(Some(Self {
+            api_keys,
+            jwt,
+            validator,
+        }))
+    }
+
+    /// Returns the principal of the request, `None` when the credentials are missing
+    /// or not valid
+    pub async fn authenticate(&self, headers: &HeaderMap) -> Result<Option<Principal>> {
+        let api_key = headers
+            .get(API_KEY_HEADER)
+            .and_then(|value| value.to_str().ok());
+        let bearer = headers
+            .get(header::AUTHORIZATION)
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| {
+                let (scheme, token) = value.split_once(' ')?;
+                scheme
+                    .eq_ignore_ascii_case(BEARER_SCHEME)
+                    .then_some(token.trim())
+            });
+
+        for key in [api_key, bearer].into_iter().flatten() {
+            let found = self
+                .api_keys
+                .iter()
+                .find(|(_, expected)| constant_time_eq(expected.as_bytes(), key.as_bytes()));
+            if let Some((name, _)) = found {
+                return Ok(Some(Principal::new(name, AuthMethod::ApiKey)));
+            }
+        }
+
+        let Some(token) = bearer else {
+            return Ok(None);
+        };
+        if let Some((key, validation)) = &self.jwt {
+            let principal = jsonwebtoken::decode::<Map<String, Value>>(token, key, validation)
+                .ok()
+                .and_then(|data| {
+                    let id = data.claims.get("sub")?.as_str()?.to_string();
+                    Some(Principal {
+                        id,
+                        method: AuthMethod::Jwt,
+                        claims: data.claims,
+                    })
+                });
+            if principal.is_some() {
+                return Ok(principal);
+            }
+        }
+        match &self.validator {
+            Some(validator) => validator.validate(token).await,
+            None => Ok(None),
+        }
+    }
+}
+
+impl JwtConfig {
+    fn decoding(&self) -> Result<(DecodingKey, Validation)> {
+        let (algorithm, key) = match self.algorithm {
+            JwtAlgorithm::HS256 => {
+                let secret = self.secret.as_ref().ok_or_else(|| {
+                    Error::Config("server.auth.jwt: HS256 needs a secret".to_string())
+                })?;
+                (
+                    Algorithm::HS256,
+                    DecodingKey::from_secret(secret.as_bytes()),
+                )
+            }
+            JwtAlgorithm::RS256 => {
+                let path = self.public_key_file.as_ref().ok_or_else(|| {
+                    Error::Config("server.auth.jwt: RS256 needs a public_key_file".to_string())
+                })?;
+                let pem = fs::read(path).map_err(|err| {
+                    Error::Config(format!("could not read public key {}: {}", path, err))
+                })?;
+                let key = DecodingKey::from_rsa_pem(&pem).map_err(|err| {
+                    Error::Config(format!("invalid public key {}: {}", path, err))
+                })?;
+                (Algorithm::RS256, key)
+            }
+        };
+
+        let mut validation = Validation::new(algorithm);
+        validation.leeway = self.leeway_secs;
+        if let Some(issuer) = &self.issuer {
+            validation.set_issuer(&[issuer]);
+        }
+        match &self.audience {
+            Some(audience) => validation.set_audience(&[audience]),
+            None => validation.validate_aud = false,
+        }
+        Ok((key, validation))
+    }
+}
+
+/// Compares the keys in a time independent of their content
+fn constant_time_eq(expected: &[u8], actual: &[u8]) -> bool {
+    expected.len() == actual.len()
+        && expected
+            .iter()
+            .zip(actual)
+            .fold(0, |diff, (a, b)| diff | (a ^ b))
+            == 0
+}
+
+/// Rejects the requests without valid credentials, and stores the principal of the
+/// others in their extensions
+pub(crate) async fn require_auth(
+    State(authenticator): State<Arc<Authenticator>>,
+    mut request: Request,
+    next: Next,
+) -> Response {
+    match authenticator.authenticate(request.headers()).await {
+        Ok(Some(principal)) => {
+            request.extensions_mut().insert(principal);
+            next.run(request).await
+        }
+        Ok(None) => (
+            StatusCode::UNAUTHORIZED,
+            [(header::WWW_AUTHENTICATE, BEARER_SCHEME)],
+            "unauthorized",
+        )
+            .into_response(),
+        Err(err) => {
+            println!("Failed to authenticate request: {}", err);
+            StatusCode::INTERNAL_SERVER_ERROR.into_response()
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::http::HeaderValue;
+    use jsonwebtoken::{EncodingKey, Header};
+    use serde_json::json;
+
+    #[tokio::test]
+    async fn accepts_api_keys_and_signed_tokens() {
+        let config = AuthConfig {
+            api_keys: HashMap::from([("ci".to_string(), "secret-key".to_string())]),
+            jwt: Some(JwtConfig {
+                algorithm: JwtAlgorithm::HS256,
+                secret: Some("jwt-secret".to_string()),
+                public_key_file: None,
+                issuer: Some("issuer".to_string()),
+                audience: None,
+                leeway_secs: DEFAULT_JWT_LEEWAY_SECS,
+            }),
+        };
+        let authenticator = Authenticator::new(&config, None).unwrap().unwrap();
+        let authenticate = |name, value: String| {
+            let mut headers = HeaderMap::new();
+            headers.insert(name, HeaderValue::from_str(&value).unwrap());
+            let authenticator = &authenticator;
+            async move { authenticator.authenticate(&headers).await.unwrap() }
+        };
+
+        let principal = authenticate(API_KEY_HEADER, "secret-key".to_string()).await;
+        assert_eq!(principal, Some(Principal::new("ci", AuthMethod::ApiKey)));
+        let principal = authenticate(
+            header::AUTHORIZATION.as_str(),
+            "bearer secret-key".to_string(),
+        )
+        .await;
+        assert_eq!(principal, Some(Principal::new("ci", AuthMethod::ApiKey)));
+        assert!(
+            authenticate(header::AUTHORIZATION.as_str(), "Bearer wrong".to_string())
+                .await
+                .is_none()
+        );
+
+        let claims = json!({"sub": "alice", "iss": "issuer", "exp": 4_102_444_800u64});
+        let token = jsonwebtoken::encode(
+            &Header::new(Algorithm::HS256),
+            &claims,
+            &EncodingKey::from_secret(b"jwt-secret"),
+        )
+        .unwrap();
+        let principal = authenticate(header::AUTHORIZATION.as_str(), format!("Bearer {}", token))
+            .await
+            .unwrap();
+        assert_eq!(principal.id, "alice");
+        assert_eq!(principal.method, AuthMethod::Jwt);
+
+        let forged = jsonwebtoken::encode(
+            &Header::new(Algorithm::HS256),
+            &claims,
+            &EncodingKey::from_secret(b"other-secret"),
+        )
+        .unwrap();
+        assert!(
+            authenticate(header::AUTHORIZATION.as_str(), format!("Bearer {}", forged))
+                .await
+                .is_none()
+        );
+    }
+}
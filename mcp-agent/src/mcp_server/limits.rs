@@ -0,0 +1,460 @@
+use super::auth::Principal;
+use crate::agent::report::{LlmCall, UsageSink, with_usage_sink};
+use axum::extract::ConnectInfo;
+use axum::http::request::Parts;
+use rmcp::model::{ClientRequest, ErrorCode, ServerInfo, ServerResult};
+use rmcp::service::{NotificationContext, RequestContext};
+use rmcp::{Error as McpError, RoleServer, Service};
+use serde::Deserialize;
+use serde_json::json;
+use std::collections::HashMap;
+use std::fmt;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+/// Error code of the tool calls rejected by a limit, in the range of the JSON-RPC
+/// server errors
+pub const LIMIT_EXCEEDED: ErrorCode = ErrorCode(-32000);
+
+const SECS_PER_DAY: u64 = 24 * 60 * 60;
+/// Time after which a token bucket is full again
+const BUCKET_REFILL: Duration = Duration::from_secs(60);
+/// Interval of the removal of the state of idle clients
+const SWEEP_INTERVAL: Duration = Duration::from_secs(60);
+const ANONYMOUS_CLIENT: &str = "anonymous";
+
+/// Limits of the tool calls of each client, read from the `[server.limits]` table
+///
+/// Clients are told apart by their authenticated principal, or by their IP address
+/// when the server does not authenticate requests.
+#[derive(Debug, Deserialize, Clone, Default, PartialEq)]
+#[serde(default)]
+pub struct LimitsConfig {
+    /// Tool calls per minute of each client
+    pub requests_per_minute: Option<u32>,
+    /// Tool calls of each client running at the same time
+    pub max_concurrent_calls: Option<usize>,
+    /// LLM tokens each client may consume per UTC day
+    pub daily_tokens: Option<u64>,
+    /// Limits of each client on single tools, read from `[server.limits.tools.<name>]`
+    pub tools: HashMap<String, ToolLimits>,
+}
+
+/// Limits of each client on a tool, in addition to the limits of all its tool calls
+#[derive(Debug, Deserialize, Clone, Default, PartialEq)]
+#[serde(default)]
+pub struct ToolLimits {
+    pub requests_per_minute: Option<u32>,
+    pub max_concurrent_calls: Option<usize>,
+}
+
+impl LimitsConfig {
+    /// Whether any limit is set
+    pub fn is_enabled(&self) -> bool {
+        self.requests_per_minute.is_some()
+            || self.max_concurrent_calls.is_some()
+            || self.daily_tokens.is_some()
+            || !self.tools.is_empty()
+    }
+}
+
+/// The limit a tool call exceeded
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Limit {
+    RequestsPerMinute,
+    ConcurrentCalls,
+    DailyTokens,
+}
+
+impl fmt::Display for Limit {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Limit::RequestsPerMinute => write!(f, "requests_per_minute"),
+            Limit::ConcurrentCalls => write!(f, "max_concurrent_calls"),
+            Limit::DailyTokens => write!(f, "daily_tokens"),
+        }
+    }
+}
+
+/// A tool call rejected by a limit
+#[derive(Debug, Clone, PartialEq)]
+pub struct LimitExceeded {
+    pub limit: Limit,
+    /// Tool the limit is set on, `None` for the limits of all the tool calls
+    pub tool: Option<String>,
+    /// When the call may be retried, `None` once another call of the client ends
+    pub retry_after: Option<Duration>,
+}
+
+impl From<LimitExceeded> for McpError {
+    fn from(exceeded: LimitExceeded) -> Self {
+        let message = match &exceeded.tool {
+            Some(tool) => format!("{} limit of tool {} exceeded", exceeded.limit, tool),
+            None => format!("{} limit exceeded", exceeded.limit),
+        };
+        McpError::new(
+            LIMIT_EXCEEDED,
+            message,
+            Some(json!({
+                "limit": exceeded.limit.to_string(),
+                "tool": exceeded.tool,
+                "retry_after_secs": exceeded.retry_after.map(|retry| retry.as_secs().max(1)),
+            })),
+        )
+    }
+}
+
+/// Rate limits, concurrency caps and daily token quotas of the clients
+#[derive(Debug)]
+pub struct RateLimiter {
+    config: LimitsConfig,
+    state: Mutex<LimiterState>,
+}
+
+/// A client, or a client on a tool
+type Key = (String, Option<String>);
+
+/// State of the clients, an entry is removed once it holds the same as no entry so
+/// that idle clients do not use memory
+#[derive(Debug)]
+struct LimiterState {
+    buckets: HashMap<Key, Bucket>,
+    running: HashMap<Key, usize>,
+    tokens: HashMap<String, DailyTokens>,
+    swept: Instant,
+}
+
+/// Token bucket refilled at `requests_per_minute`, up to a minute of calls
+#[derive(Debug)]
+struct Bucket {
+    available: f64,
+    updated: Instant,
+}
+
+#[derive(Debug)]
+struct DailyTokens {
+    day: u64,
+    used: u64,
+}
+
+/// Keeps a tool call counted as running until dropped
+#[derive(Debug)]
+pub struct CallPermit {
+    limiter: Arc<RateLimiter>,
+    keys: Vec<Key>,
+}
+
+impl RateLimiter {
+    /// Creates a limiter enforcing the limits
+    pub fn new(config: LimitsConfig) -> Self {
+        Self {
+            config,
+            state: Mutex::new(LimiterState {
+                buckets: HashMap::new(),
+                running: HashMap::new(),
+                tokens: HashMap::new(),
+                swept: Instant::now(),
+            }),
+        }
+    }
+
+    /// Admits a tool call of the client, unless it exceeds a limit
+    pub fn acquire(
+        self: &Arc<Self>,
+        client: &str,
+        tool: &str,
+    ) -> Result<CallPermit, LimitExceeded> {
+        let mut state = self.state.lock().unwrap_or_else(|err| err.into_inner());
+        let now = Instant::now();
+        let (day, secs_to_midnight) = utc_day();
+        if now.duration_since(state.swept) >= SWEEP_INTERVAL {
+            state.sweep(now, day);
+        }
+
+        if let Some(quota) = self.config.daily_tokens {
+            let used = state
+                .tokens
+                .get(client)
+                .filter(|tokens| tokens.day == day)
+                .map_or(0, |tokens| tokens.used);
+            if used >= quota {
+                return Err(LimitExceeded {
+                    limit: Limit::DailyTokens,
+                    tool: None,
+                    retry_after: Some(Duration::from_secs(secs_to_midnight)),
+                });
+            }
+        }
+
+        let tool_limits = self.config.tools.get(tool);
+        let limits = [
+            (
+                (client.to_string(), None),
+                self.config.requests_per_minute,
+                self.config.max_concurrent_calls,
+            ),
+            (
+                (client.to_string(), Some(tool.to_string())),
+                tool_limits.and_then(|limits| limits.requests_per_minute),
+                tool_limits.and_then(|limits| limits.max_concurrent_calls),
+            ),
+        ];
+
+        // checks every limit before counting the call in any of them
+        for (key, requests_per_minute, max_concurrent_calls) in &limits {
+            let exceeded = |limit, retry_after| LimitExceeded {
+                limit,
+                tool: key.1.clone(),
+                retry_after,
+            };
+            let running = state.running.get(key).copied().unwrap_or(0);
+            if max_concurrent_calls.is_some_and(|max| running >= max) {
+                return Err(exceeded(Limit::ConcurrentCalls, None));
+            }
+            if let Some(per_minute) = requests_per_minute {
+                let available = state.buckets.get(key).map_or(*per_minute as f64, |bucket| {
+                    bucket.refilled(*per_minute, now)
+                });
+                if available < 1.0 {
+                    let wait = (1.0 - available) * 60.0 / *per_minute as f64;
+                    return Err(exceeded(
+                        Limit::RequestsPerMinute,
+                        Some(Duration::from_secs_f64(wait)),
+                    ));
+                }
+            }
+        }
+
+        let mut keys = Vec::new();
+        for (key, requests_per_minute, max_concurrent_calls) in limits {
+            if let Some(per_minute) = requests_per_minute {
+                let bucket = state.buckets.entry(key.clone()).or_insert(Bucket {
+                    available: per_minute as f64,
+                    updated: now,
+                });
+                bucket.available = bucket.refilled(per_minute, now) - 1.0;
+                bucket.updated = now;
+            }
+            if max_concurrent_calls.is_some() {
+                *state.running.entry(key.clone()).or_default() += 1;
+                keys.push(key);
+            }
+        }
+        Ok(CallPermit {
+            limiter: self.clone(),
+            keys,
+        })
+    }
+
+    /// Charges LLM tokens to the daily quota of the client
+    pub fn record_tokens(&self, client: &str, tokens: u64) {
+        if self.config.daily_tokens.is_none() {
+            return;
+        }
+        let (day, _) = utc_day();
+        let mut state = self.state.lock().unwrap_or_else(|err| err.into_inner());
+        let entry = state
+            .tokens
+            .entry(client.to_string())
+            .or_insert(DailyTokens { day, used: 0 });
+        if entry.day != day {
+            *entry = DailyTokens { day, used: 0 };
+        }
+        entry.used += tokens;
+    }
+}
+
+impl LimiterState {
+    /// Removes the full buckets and the tokens of past days, running counts are
+    /// removed when they drop to zero
+    fn sweep(&mut self, now: Instant, day: u64) {
+        self.buckets
+            .retain(|_, bucket| now.duration_since(bucket.updated) < BUCKET_REFILL);
+        self.tokens.retain(|_, tokens| tokens.day == day);
+        self.swept = now;
+    }
+}
+
+impl Bucket {
+    fn refilled(&self, per_minute: u32, now: Instant) -> f64 {
+        let elapsed = now.duration_since(self.updated).as_secs_f64();
+        (self.available + elapsed * per_minute as f64 / 60.0).min(per_minute as f64)
+    }
+}
+
+impl Drop for CallPermit {
+    fn drop(&mut self) {
+        let mut state = self
+            .limiter
+            .state
+            .lock()
+            .unwrap_or_else(|err| err.into_inner());
+        for key in &self.keys {
+            if let Some(running) = state.running.get_mut(key) {
+                *running -= 1;
+                if *running == 0 {
+                    state.running.remove(key);
+                }
+            }
+        }
+    }
+}
+
+/// Returns the current UTC day and the seconds until the next one
+fn utc_day() -> (u64, u64) {
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs();
+    (now / SECS_PER_DAY, SECS_PER_DAY - now % SECS_PER_DAY)
+}
+
+/// Returns the client the limits of the request apply to: its principal, else its
+/// IP address when the server records the connection info
+pub fn client_id(context: &RequestContext<RoleServer>) -> String {
+    let Some(parts) = context.extensions.get::<Parts>() else {
+        return ANONYMOUS_CLIENT.to_string();
+    };
+    if let Some(principal) = parts.extensions.get::<Principal>() {
+        return format!("principal:{}", principal.id);
+    }
+    match parts.extensions.get::<ConnectInfo<SocketAddr>>() {
+        Some(ConnectInfo(addr)) => format!("ip:{}", addr.ip()),
+        None => ANONYMOUS_CLIENT.to_string(),
+    }
+}
+
+/// A service whose tool calls go through the limiter, tokens of the LLM responses of
+/// a call are charged to the daily quota of its client
+///
+/// The quota is checked when a call starts, so the call that reaches it completes.
+pub struct Limited<S> {
+    inner: S,
+    limiter: Option<Arc<RateLimiter>>,
+}
+
+impl<S> Limited<S> {
+    /// Wraps the service, which is left unlimited without limiter
+    pub fn new(inner: S, limiter: Option<Arc<RateLimiter>>) -> Self {
+        Self { inner, limiter }
+    }
+}
+
+impl<S: Service<RoleServer>> Service<RoleServer> for Limited<S> {
+    async fn handle_request(
+        &self,
+        request: ClientRequest,
+        context: RequestContext<RoleServer>,
+    ) -> Result<ServerResult, McpError> {
+        let (Some(limiter), ClientRequest::CallToolRequest(call)) = (&self.limiter, &request)
+        else {
+            return self.inner.handle_request(request, context).await;
+        };
+
+        let client = client_id(&context);
+        let permit = limiter
+            .acquire(&client, &call.params.name)
+            .inspect_err(|exceeded| {
+                println!(
+                    "Rejected tool call {} of {}: {} limit exceeded",
+                    call.params.name, client, exceeded.limit
+                );
+            })?;
+        let sink: UsageSink = {
+            let limiter = limiter.clone();
+            Arc::new(move |call: &LlmCall| {
+                limiter.record_tokens(&client, call.usage.total_tokens() as u64)
+            })
+        };
+        let result = with_usage_sink(sink, self.inner.handle_request(request, context)).await;
+        drop(permit);
+        result
+    }
+
+    async fn handle_notification(
+        &self,
+        notification: <RoleServer as rmcp::service::ServiceRole>::PeerNot,
+        context: NotificationContext<RoleServer>,
+    ) -> Result<(), McpError> {
+        self.inner.handle_notification(notification, context).await
+    }
+
+    fn get_info(&self) -> ServerInfo {
+        self.inner.get_info()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn enforces_rates_concurrency_and_token_quotas() {
+        let config: LimitsConfig = toml::from_str(
+            r#"
+            requests_per_minute = 3
+            daily_tokens = 1000
+
+            [tools.search]
+            max_concurrent_calls = 1
+            "#,
+        )
+        .unwrap();
+        let limiter = Arc::new(RateLimiter::new(config));
+
+        let search = limiter.acquire("alice", "search").unwrap();
+        let exceeded = limiter.acquire("alice", "search").unwrap_err();
+        assert_eq!(exceeded.limit, Limit::ConcurrentCalls);
+        assert_eq!(exceeded.tool.as_deref(), Some("search"));
+        drop(search);
+
+        limiter.acquire("alice", "search").unwrap();
+        limiter.acquire("alice", "other").unwrap();
+        let exceeded = limiter.acquire("alice", "other").unwrap_err();
+        assert_eq!(exceeded.limit, Limit::RequestsPerMinute);
+        assert!(exceeded.retry_after.unwrap() <= Duration::from_secs(20));
+
+        limiter.record_tokens("bob", 1200);
+        let exceeded = limiter.acquire("bob", "other").unwrap_err();
+        assert_eq!(exceeded.limit, Limit::DailyTokens);
+        assert_eq!(McpError::from(exceeded).code, LIMIT_EXCEEDED);
+    }
+
+    #[test]
+    fn forgets_idle_clients() {
+        let config: LimitsConfig = toml::from_str(
+            r#"
+            requests_per_minute = 3
+            daily_tokens = 1000
+
+            [tools.search]
+            max_concurrent_calls = 1
+            "#,
+        )
+        .unwrap();
+        let limiter = Arc::new(RateLimiter::new(config));
+
+        let permit = limiter.acquire("alice", "search").unwrap();
+        limiter.record_tokens("alice", 10);
+        limiter.record_tokens("bob", 10);
+        {
+            let state = limiter.state.lock().unwrap();
+            assert_eq!(state.buckets.len(), 1);
+            assert_eq!(state.running.len(), 1);
+            assert_eq!(state.tokens.len(), 2);
+        }
+        drop(permit);
+
+        let mut state = limiter.state.lock().unwrap();
+        assert!(state.running.is_empty());
+        let (day, _) = utc_day();
+        state.tokens.get_mut("bob").unwrap().day = day - 1;
+        state.sweep(Instant::now(), day);
+        assert_eq!(state.buckets.len(), 1);
+        assert_eq!(state.tokens.keys().collect::<Vec<_>>(), ["alice"]);
+
+        state.sweep(Instant::now() + BUCKET_REFILL, day);
+        assert!(state.buckets.is_empty());
+    }
+}
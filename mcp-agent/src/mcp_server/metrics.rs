@@ -0,0 +1,115 @@
+use axum::response::Response;
+use std::fmt::Write;
+use std::sync::atomic::{AtomicI64, AtomicU64, Ordering};
+
+/// The HTTP transports of the MCP server
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Transport {
+    Sse,
+    Streamable,
+}
+
+impl Transport {
+    fn label(self) -> &'static str {
+        match self {
+            Transport::Sse => "sse",
+            Transport::Streamable => "streamable",
+        }
+    }
+}
+
+/// Request counters of the HTTP transports, served in the Prometheus text format
+///
+/// SSE streams count as in flight until their response starts, not until they close.
+#[derive(Debug, Default)]
+pub struct HttpMetrics {
+    sse_requests: AtomicU64,
+    streamable_requests: AtomicU64,
+    failed_requests: AtomicU64,
+    in_flight: AtomicI64,
+}
+
+impl HttpMetrics {
+    /// Counts a request of the transport while its response is produced
+    pub async fn track(
+        &self,
+        transport: Transport,
+        response: impl Future<Output = Response>,
+    ) -> Response {
+        match transport {
+            Transport::Sse => &self.sse_requests,
+            Transport::Streamable => &self.streamable_requests,
+        }
+        .fetch_add(1, Ordering::Relaxed);
+
+        self.in_flight.fetch_add(1, Ordering::Relaxed);
+        let response = response.await;
+        self.in_flight.fetch_sub(1, Ordering::Relaxed);
+
+        if response.status().is_server_error() {
+            self.failed_requests.fetch_add(1, Ordering::Relaxed);
+        }
+        response
+    }
+
+    /// Renders the counters in the Prometheus text format
+    pub fn render(&self) -> String {
+        let mut text = String::new();
+        text.push_str(
+            "# HELP mcp_agent_http_requests_total HTTP requests received by the MCP transports\n",
+        );
+        text.push_str("# TYPE mcp_agent_http_requests_total counter\n");
+        for (transport, requests) in [
+            (Transport::Sse, &self.sse_requests),
+            (Transport::Streamable, &self.streamable_requests),
+        ] {
+            let _ = writeln!(
+                text,
+                "mcp_agent_http_requests_total{{transport=\"{}\"}} {}",
+                transport.label(),
+                requests.load(Ordering::Relaxed)
+            );
+        }
+        text.push_str("# HELP mcp_agent_http_requests_failed_total HTTP requests answered with a server error\n");
+        text.push_str("# TYPE mcp_agent_http_requests_failed_total counter\n");
+        let _ = writeln!(
+            text,
+            "mcp_agent_http_requests_failed_total {}",
+            self.failed_requests.load(Ordering::Relaxed)
+        );
+        text.push_str("# HELP mcp_agent_http_requests_in_flight HTTP requests being handled\n");
+        text.push_str("# TYPE mcp_agent_http_requests_in_flight gauge\n");
+        let _ = writeln!(
+            text,
+            "mcp_agent_http_requests_in_flight {}",
+            self.in_flight.load(Ordering::Relaxed)
+        );
+        text
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::http::StatusCode;
+    use axum::response::IntoResponse;
+
+    #[tokio::test]
+    async fn counts_requests_per_transport() {
+        let metrics = HttpMetrics::default();
+        metrics
+            .track(Transport::Sse, async { StatusCode::OK.into_response() })
+            .await;
+        metrics
+            .track(Transport::Streamable, async {
+                StatusCode::INTERNAL_SERVER_ERROR.into_response()
+            })
+            .await;
+
+        let text = metrics.render();
+        assert!(text.contains("mcp_agent_http_requests_total{transport=\"sse\"} 1\n"));
+        assert!(text.contains("mcp_agent_http_requests_total{transport=\"streamable\"} 1\n"));
+        assert!(text.contains("mcp_agent_http_requests_failed_total 1\n"));
+        assert!(text.contains("mcp_agent_http_requests_in_flight 0\n"));
+    }
+}
@@ -1 +1,9 @@
+pub mod auth;
+pub mod limits;
+pub mod metrics;
+pub mod progress;
 pub mod server;
+pub mod session;
+pub use auth::{Principal, TokenValidator, principal};
+pub use server::{Server, ServerBuilder, ServerConfig};
+pub use session::{McpSession, mcp_session_id};
@@ -0,0 +1,162 @@
+use crate::agent::{Agent, AgentEvent};
+use crate::error::{Error, Result};
+use crate::llm::message::Conversation;
+use futures::{StreamExt, pin_mut};
+use rmcp::RoleServer;
+use rmcp::model::{ProgressNotificationParam, ProgressToken};
+use rmcp::service::{Peer, RequestContext};
+
+/// Forwards the steps of an agent run to the MCP client as `notifications/progress`
+///
+/// Progress is best effort: a notification that cannot be delivered is logged and
+/// does not fail the run.
+pub struct ProgressReporter {
+    peer: Peer<RoleServer>,
+    token: ProgressToken,
+    progress: u32,
+}
+
+impl ProgressReporter {
+    /// Creates a reporter sending notifications for the given progress token
+    pub fn new(context: &RequestContext<RoleServer>, token: ProgressToken) -> Self {
+        Self {
+            peer: context.peer.clone(),
+            token,
+            progress: 0,
+        }
+    }
+
+    /// Creates a reporter for the progress token of the request, if the client sent one
+    pub fn from_context(context: &RequestContext<RoleServer>) -> Option<Self> {
+        let token = context.meta.get_progress_token()?;
+        Some(Self::new(context, token))
+    }
+
+    /// Sends a progress notification with the given message
+    pub async fn notify(&mut self, message: impl Into<String>) {
+        self.progress += 1;
+        let param = ProgressNotificationParam {
+            progress_token: self.token.clone(),
+            progress: self.progress,
+            total: None,
+            message: Some(message.into()),
+        };
+        if let Err(err) = self.peer.notify_progress(param).await {
+            println!("Failed to send progress notification: {}", err);
+        }
+    }
+
+    /// Sends a progress notification describing the agent event, if it is a step
+    pub async fn report(&mut self, event: &AgentEvent, max_turns: Option<usize>) {
+        let message = match event {
+            AgentEvent::TurnStarted { turn } => match max_turns {
+                Some(max) => format!("turn {} of {}", turn, max),
+                None => format!("turn {}", turn),
+            },
+            AgentEvent::ToolCallStarted { name, .. } => format!("calling {}", name),
+            AgentEvent::ToolResult {
+                name,
+                is_error: true,
+                ..
+            } => format!("{} failed", name),
+            AgentEvent::LlmAttempt(attempt) if attempt.error.is_some() => format!(
+                "llm [{}] attempt {} failed",
+                attempt.profile, attempt.attempt
+            ),
+            AgentEvent::FinalAnswer(_) => "done".to_string(),
+            AgentEvent::LimitReached { limit, .. } => format!("stopped: {}", limit),
+            _ => return,
+        };
+        self.notify(message).await;
+    }
+
+    /// Sends the conversation through the agent and reports each step as progress
+    ///
+    /// Returns the final answer like [`Agent::send`].
+    pub async fn send(&mut self, agent: &Agent, conversation: &mut Conversation) -> Result<String> {
+        let max_turns = agent.loop_policy().max_turns;
+        let events = agent.send_stream(conversation);
+        pin_mut!(events);
+        while let Some(event) = events.next().await {
+            let event = event?;
+            self.report(&event, max_turns).await;
+            match event {
+                AgentEvent::FinalAnswer(answer) => return Ok(answer),
+                AgentEvent::LimitReached { limit, .. } => return Err(Error::LoopLimit(limit)),
+                _ => {}
+            }
+        }
+        Err(Error::Llm("agent run ended without an answer".to_string()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::agent::testing::{ECHO_CONFIG, EchoServer, ScriptedLlm, tool_call};
+    use rmcp::model::{NumberOrString, ProgressNotificationParam};
+    use rmcp::service::{NotificationContext, ServiceExt};
+    use rmcp::{ClientHandler, RoleClient};
+    use std::time::Duration;
+    use tokio::sync::mpsc;
+
+    /// MCP client forwarding the progress notifications it receives
+    struct ProgressRecorder(mpsc::UnboundedSender<ProgressNotificationParam>);
+
+    impl ClientHandler for ProgressRecorder {
+        async fn on_progress(
+            &self,
+            params: ProgressNotificationParam,
+            _context: NotificationContext<RoleClient>,
+        ) {
+            let _ = self.0.send(params);
+        }
+    }
+
+    #[tokio::test]
+    async fn reports_agent_steps_as_progress() {
+        let server = EchoServer::new(&["echo"]);
+        let llm = ScriptedLlm::new(vec![vec![tool_call("call_0", "echo", 0)]], "answer");
+        let agent = Agent::with_echo_server(ECHO_CONFIG, &server, llm).await;
+
+        let (client_io, server_io) = tokio::io::duplex(4096);
+        let service = tokio::spawn(EchoServer::default().serve(server_io));
+        let (sender, mut notifications) = mpsc::unbounded_channel();
+        let _client = ProgressRecorder(sender).serve(client_io).await.unwrap();
+        let service = service.await.unwrap().unwrap();
+        let context = RequestContext {
+            ct: Default::default(),
+            id: NumberOrString::Number(1),
+            meta: Default::default(),
+            extensions: Default::default(),
+            peer: service.peer().clone(),
+        };
+
+        let token = ProgressToken(NumberOrString::Number(7));
+        let mut reporter = ProgressReporter::new(&context, token.clone());
+        let mut conversation = Conversation::new(256);
+        conversation.append_user_content("hello".to_string());
+        let answer = reporter.send(&agent, &mut conversation).await.unwrap();
+        assert_eq!(answer, "answer");
+
+        let mut received = Vec::new();
+        for _ in 0..4 {
+            let param = tokio::time::timeout(Duration::from_secs(5), notifications.recv())
+                .await
+                .unwrap()
+                .unwrap();
+            assert_eq!(param.progress_token, token);
+            assert_eq!(param.total, None);
+            received.push((param.progress, param.message.unwrap()));
+        }
+        assert_eq!(
+            received,
+            [
+                (1, "turn 1 of 16".to_string()),
+                (2, "calling echo".to_string()),
+                (3, "turn 2 of 16".to_string()),
+                (4, "done".to_string()),
+            ]
+        );
+    }
+}
@@ -1,52 +1,315 @@
+use super::auth::{self, AuthConfig, Authenticator, TokenValidator};
+use super::limits::{Limited, LimitsConfig, RateLimiter};
+use super::metrics::{HttpMetrics, Transport};
 use crate::agent::{Agent, Config};
+use crate::error::{Error, Result};
+use axum::body::{Bytes, HttpBody};
+use axum::extract::Request;
+use axum::http::header;
+use axum::middleware::{self, Next};
+use axum::routing::{RouterIntoService, get};
+use axum::{BoxError, Router};
 use rmcp::transport::sse_server::SseServerConfig;
 use rmcp::transport::streamable_http_server::{
-    StreamableHttpService, session::local::LocalSessionManager,
+    StreamableHttpServerConfig, StreamableHttpService, session::local::LocalSessionManager,
 };
 use rmcp::transport::{SseServer, stdio};
 use rmcp::{RoleServer, Service, ServiceExt};
-use std::io::Error;
+use serde::Deserialize;
+use std::net::SocketAddr;
 use std::sync::Arc;
 use std::time::Duration;
+use tokio_util::sync::CancellationToken;
 
-const BIND_ADDRESS: &str = "0.0.0.0:8080";
-const SSE_PATH: &str = "/sse";
-const MESSAGE_PATH: &str = "/message";
-const STREAMABLE_PATH: &str = "/mcp";
+const DEFAULT_CONFIG_PATH: &str = "mcp-agent.toml";
+const DEFAULT_BIND_ADDRESS: &str = "0.0.0.0:8080";
+const DEFAULT_SSE_PATH: &str = "/sse";
+const DEFAULT_MESSAGE_PATH: &str = "/message";
+const DEFAULT_STREAMABLE_PATH: &str = "/mcp";
+const DEFAULT_KEEP_ALIVE_SECS: u64 = 10;
+
+/// Settings of the HTTP transports, read from the `[server]` table
+#[derive(Debug, Deserialize, Clone, PartialEq)]
+#[serde(default)]
+pub struct ServerConfig {
+    /// Address the SSE and streamable HTTP transports listen on
+    pub bind_address: String,
+    /// Path of the SSE stream
+    pub sse_path: String,
+    /// Path the SSE clients post their messages to
+    pub message_path: String,
+    /// Path of the streamable HTTP endpoint
+    pub streamable_path: String,
+    /// Interval of the keep-alive pings sent on SSE streams, 0 disables them
+    pub keep_alive_secs: u64,
+    /// Path of the health route of [`Server::serve_http`], disabled when unset
+    pub health_path: Option<String>,
+    /// Path of the Prometheus metrics route of [`Server::serve_http`], disabled when unset
+    pub metrics_path: Option<String>,
+    /// Authentication of the transports, read from the `[server.auth]` table
+    pub auth: AuthConfig,
+    /// Limits of the tool calls of each client, read from the `[server.limits]` table
+    pub limits: LimitsConfig,
+}
+
+impl Default for ServerConfig {
+    fn default() -> Self {
+        Self {
+            bind_address: DEFAULT_BIND_ADDRESS.to_string(),
+            sse_path: DEFAULT_SSE_PATH.to_string(),
+            message_path: DEFAULT_MESSAGE_PATH.to_string(),
+            streamable_path: DEFAULT_STREAMABLE_PATH.to_string(),
+            keep_alive_secs: DEFAULT_KEEP_ALIVE_SECS,
+            health_path: None,
+            metrics_path: None,
+            auth: AuthConfig::default(),
+            limits: LimitsConfig::default(),
+        }
+    }
+}
+
+impl ServerConfig {
+    /// Returns the parsed bind address
+    pub fn bind_address(&self) -> Result<SocketAddr> {
+        self.bind_address.parse().map_err(|err| {
+            Error::Config(format!(
+                "invalid bind address {}: {}",
+                self.bind_address, err
+            ))
+        })
+    }
+
+    /// Returns the keep-alive interval, `None` when disabled
+    pub fn keep_alive(&self) -> Option<Duration> {
+        (self.keep_alive_secs > 0).then(|| Duration::from_secs(self.keep_alive_secs))
+    }
+
+    /// Checks the bind address and that the paths are absolute
+    pub fn validate(&self) -> Result<()> {
+        self.bind_address()?;
+        let paths = [&self.sse_path, &self.message_path, &self.streamable_path]
+            .into_iter()
+            .chain(self.health_path.as_ref())
+            .chain(self.metrics_path.as_ref());
+        for path in paths {
+            if !path.starts_with('/') {
+                return Err(Error::Config(format!(
+                    "server path {} must start with /",
+                    path
+                )));
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Builds a [`Server`], the settings given to the builder override the `[server]`
+/// table of the configuration
+pub struct ServerBuilder {
+    config_path: String,
+    agent: Option<Arc<Agent>>,
+    bind_address: Option<String>,
+    sse_path: Option<String>,
+    message_path: Option<String>,
+    streamable_path: Option<String>,
+    keep_alive_secs: Option<u64>,
+    health_path: Option<String>,
+    metrics_path: Option<String>,
+    validator: Option<Arc<dyn TokenValidator>>,
+    ct: Option<CancellationToken>,
+}
+
+impl ServerBuilder {
+    /// Creates a builder reading the configuration from mcp-agent.toml
+    pub fn new() -> Self {
+        Self {
+            config_path: DEFAULT_CONFIG_PATH.to_string(),
+            agent: None,
+            bind_address: None,
+            sse_path: None,
+            message_path: None,
+            streamable_path: None,
+            keep_alive_secs: None,
+            health_path: None,
+            metrics_path: None,
+            validator: None,
+            ct: None,
+        }
+    }
+
+    /// Sets the configuration file the agent is created from
+    pub fn config_path(mut self, path: impl Into<String>) -> Self {
+        self.config_path = path.into();
+        self
+    }
+
+    /// Serves an already-built agent instead of reading the configuration file,
+    /// the `[server]` table of its configuration still applies
+    pub fn agent(mut self, agent: Arc<Agent>) -> Self {
+        self.agent = Some(agent);
+        self
+    }
+
+    /// Sets the address the HTTP transports listen on, e.g. `127.0.0.1:3000`
+    pub fn bind_address(mut self, addr: impl Into<String>) -> Self {
+        self.bind_address = Some(addr.into());
+        self
+    }
+
+    /// Sets the path of the SSE stream
+    pub fn sse_path(mut self, path: impl Into<String>) -> Self {
+        self.sse_path = Some(path.into());
+        self
+    }
+
+    /// Sets the path the SSE clients post their messages to
+    pub fn message_path(mut self, path: impl Into<String>) -> Self {
+        self.message_path = Some(path.into());
+        self
+    }
+
+    /// Sets the path of the streamable HTTP endpoint
+    pub fn streamable_path(mut self, path: impl Into<String>) -> Self {
+        self.streamable_path = Some(path.into());
+        self
+    }
+
+    /// Sets the interval of the SSE keep-alive pings, 0 disables them
+    pub fn keep_alive_secs(mut self, secs: u64) -> Self {
+        self.keep_alive_secs = Some(secs);
+        self
+    }
+
+    /// Enables the health route of [`Server::serve_http`] on the path
+    pub fn health_path(mut self, path: impl Into<String>) -> Self {
+        self.health_path = Some(path.into());
+        self
+    }
+
+    /// Enables the Prometheus metrics route of [`Server::serve_http`] on the path
+    pub fn metrics_path(mut self, path: impl Into<String>) -> Self {
+        self.metrics_path = Some(path.into());
+        self
+    }
+
+    /// Checks the bearer tokens that the API keys and JWT verification of
+    /// `[server.auth]` do not accept
+    ///
+    /// The service provider is called without the [`Principal`](super::Principal) of
+    /// the request, tools read it from their request context with
+    /// [`principal`](super::principal).
+    pub fn token_validator(mut self, validator: Arc<dyn TokenValidator>) -> Self {
+        self.validator = Some(validator);
+        self
+    }
+
+    /// Stops the server once the token is cancelled, in addition to ctrl-c
+    pub fn cancellation_token(mut self, ct: CancellationToken) -> Self {
+        self.ct = Some(ct);
+        self
+    }
+
+    /// Creates the agent unless one was given, and checks the server settings
+    pub async fn build(self) -> Result<Server> {
+        let agent = match self.agent {
+            Some(agent) => agent,
+            None => Arc::new(Agent::new_with_config(Config::from_file(&self.config_path)?).await?),
+        };
+
+        let mut config = agent.config().server.clone();
+        if let Some(bind_address) = self.bind_address {
+            config.bind_address = bind_address;
+        }
+        if let Some(sse_path) = self.sse_path {
+            config.sse_path = sse_path;
+        }
+        if let Some(message_path) = self.message_path {
+            config.message_path = message_path;
+        }
+        if let Some(streamable_path) = self.streamable_path {
+            config.streamable_path = streamable_path;
+        }
+        if let Some(keep_alive_secs) = self.keep_alive_secs {
+            config.keep_alive_secs = keep_alive_secs;
+        }
+        if let Some(health_path) = self.health_path {
+            config.health_path = Some(health_path);
+        }
+        if let Some(metrics_path) = self.metrics_path {
+            config.metrics_path = Some(metrics_path);
+        }
+        config.validate()?;
+        let authenticator = Authenticator::new(&config.auth, self.validator)?.map(Arc::new);
+        let limiter = config
+            .limits
+            .is_enabled()
+            .then(|| Arc::new(RateLimiter::new(config.limits.clone())));
+
+        Ok(Server {
+            agent,
+            authenticator,
+            limiter,
+            addr: config.bind_address()?,
+            config,
+            ct: self.ct.unwrap_or_default(),
+        })
+    }
+}
+
+impl Default for ServerBuilder {
+    fn default() -> Self {
+        Self::new()
+    }
+}
 
 /// Represents a server that handles different types of MCP transport protocols
 pub struct Server {
     agent: Arc<Agent>,
+    config: ServerConfig,
+    addr: SocketAddr,
+    authenticator: Option<Arc<Authenticator>>,
+    limiter: Option<Arc<RateLimiter>>,
+    ct: CancellationToken,
 }
 
 impl Server {
     /// Creates a new server instance with configuration from mcp-agent.toml
-    pub async fn new() -> Self {
-        let agent = Arc::new(Agent::new_with_config(Config::from_file("mcp-agent.toml")).await);
-        Self { agent }
+    pub async fn new() -> Result<Self> {
+        ServerBuilder::new().build().await
+    }
+
+    /// Returns a builder to configure the server
+    pub fn builder() -> ServerBuilder {
+        ServerBuilder::new()
+    }
+
+    /// Returns the settings of the HTTP transports
+    pub fn config(&self) -> &ServerConfig {
+        &self.config
+    }
+
+    /// Returns the token stopping the server once cancelled
+    pub fn cancellation_token(&self) -> CancellationToken {
+        self.ct.clone()
     }
 
     /// Handles Server-Sent Events (SSE) transport protocol
-    /// 
+    ///
+    /// Runs until ctrl-c or the cancellation token of the server is cancelled.
+    ///
     /// # Arguments
-    /// * `addr` - The address to bind the server to
     /// * `service_provider` - A function that creates a service instance for the agent
-    pub async fn handle_sse<S, F>(&mut self, addr: &str, service_provider: F) -> Result<(), Error>
+    pub async fn handle_sse<S, F>(&mut self, service_provider: F) -> Result<()>
     where
         S: Service<RoleServer>,
         F: Fn(Arc<Agent>) -> S + Send + Sync + 'static,
     {
-        let config = SseServerConfig {
-            bind: addr.parse().unwrap(),
-            sse_path: SSE_PATH.to_string(),
-            post_path: MESSAGE_PATH.to_string(),
-            ct: Default::default(),
-            sse_keep_alive: Some(Duration::from_secs(10)),
-        };
-
-        let (sse_server, router) = SseServer::new(config);
-        let listener = tokio::net::TcpListener::bind(BIND_ADDRESS).await?;
+        let (sse_server, router) = SseServer::new(self.sse_config());
+        let router = self.authenticate(router);
+        let listener = tokio::net::TcpListener::bind(sse_server.config.bind).await?;
+        println!("SSE server listening on {}", sse_server.config.bind);
         let ct = sse_server.config.ct.child_token();
+        let router = router.into_make_service_with_connect_info::<SocketAddr>();
         let server = axum::serve(listener, router).with_graceful_shutdown(async move {
             ct.cancelled().await;
             println!("sse mcp_server cancelled");
@@ -58,78 +321,391 @@ impl Server {
             }
         });
 
-        let service_provider = Arc::new(service_provider);
-        let agent = self.agent.clone();
-        let provider = {
-            let service_provider = service_provider.clone();
-            move || service_provider(agent.clone())
-        };
-        let ct = sse_server.with_service(provider);
-        tokio::signal::ctrl_c().await?;
-        ct.cancel();
-        println!("ctrl-c received!");
+        let provider = self.provider(service_provider);
+        sse_server.with_service(provider);
+        shutdown_signal(self.ct.clone()).await;
         Ok(())
     }
 
     /// Handles streamable HTTP transport protocol
-    /// 
+    ///
+    /// Runs until ctrl-c or the cancellation token of the server is cancelled.
+    ///
     /// # Arguments
-    /// * `addr` - The address to bind the server to
     /// * `service_provider` - A function that creates a service instance for the agent
-    pub async fn handle_streamable<S, F>(
-        &mut self,
-        addr: &str,
-        service_provider: F,
-    ) -> Result<(), Error>
+    pub async fn handle_streamable<S, F>(&mut self, service_provider: F) -> Result<()>
     where
         S: Service<RoleServer>,
         F: Fn(Arc<Agent>) -> S + Send + Sync + 'static,
     {
-        let service_provider = Arc::new(service_provider);
-        let agent = self.agent.clone();
-        let provider = {
-            let service_provider = service_provider.clone();
-            move || service_provider(agent.clone())
-        };
+        let provider = self.provider(service_provider);
 
         let service = StreamableHttpService::new(
             provider,
             LocalSessionManager::default().into(),
-            Default::default(),
+            self.streamable_config(),
         );
 
-        let router = axum::Router::new().nest_service(STREAMABLE_PATH, service);
-        let tcp_listener = tokio::net::TcpListener::bind(addr).await?;
+        let router =
+            self.authenticate(Router::new().nest_service(&self.config.streamable_path, service));
+        let tcp_listener = tokio::net::TcpListener::bind(self.addr).await?;
+        println!("streamable HTTP server listening on {}", self.addr);
+        let router = router.into_make_service_with_connect_info::<SocketAddr>();
         let _ = axum::serve(tcp_listener, router)
-            .with_graceful_shutdown(async { tokio::signal::ctrl_c().await.unwrap() })
+            .with_graceful_shutdown(shutdown_signal(self.ct.clone()))
             .await;
         Ok(())
     }
 
+    /// Serves the SSE and streamable HTTP transports on one listener, with the health
+    /// and metrics routes when their paths are set
+    ///
+    /// Both transports share the agent and stop together on ctrl-c or once the
+    /// cancellation token of the server is cancelled.
+    ///
+    /// # Arguments
+    /// * `service_provider` - A function that creates a service instance for the agent
+    pub async fn serve_http<S, F>(&mut self, service_provider: F) -> Result<()>
+    where
+        S: Service<RoleServer>,
+        F: Fn(Arc<Agent>) -> S + Send + Sync + 'static,
+    {
+        let router = self.router(service_provider);
+        let listener = tokio::net::TcpListener::bind(self.addr).await?;
+        println!("MCP HTTP server listening on {}", self.addr);
+        let router = router.into_make_service_with_connect_info::<SocketAddr>();
+        axum::serve(listener, router)
+            .with_graceful_shutdown(shutdown_signal(self.ct.clone()))
+            .await?;
+        Ok(())
+    }
+
+    /// Returns the routes of [`Server::serve_http`] to mount in an existing axum app,
+    /// which then owns the listener, the middleware and TLS termination
+    ///
+    /// The bind address is not used. The SSE sessions stop once the cancellation token
+    /// of the server is cancelled, e.g. from the graceful shutdown of the app. The
+    /// limits of unauthenticated clients apply by IP address when the app is served
+    /// with `into_make_service_with_connect_info::<SocketAddr>()`. It must be called
+    /// within a tokio runtime.
+    ///
+    /// The router can be nested under a prefix with `Router::nest`: the SSE endpoint
+    /// event then advertises the message path with the prefix, e.g. `/agent/message`.
+    /// Merged with `Router::merge`, the paths of the configuration must include the
+    /// prefix instead.
+    ///
+    /// # Arguments
+    /// * `service_provider` - A function that creates a service instance for the agent
+    pub fn into_router<S, F>(self, service_provider: F) -> Router
+    where
+        S: Service<RoleServer>,
+        F: Fn(Arc<Agent>) -> S + Send + Sync + 'static,
+    {
+        self.router(service_provider)
+    }
+
+    /// Returns the routes of [`Server::into_router`] as a tower `Service`, e.g. for
+    /// hyper or another tower-based stack
+    ///
+    /// # Arguments
+    /// * `service_provider` - A function that creates a service instance for the agent
+    pub fn into_service<S, F, B>(self, service_provider: F) -> RouterIntoService<B>
+    where
+        S: Service<RoleServer>,
+        F: Fn(Arc<Agent>) -> S + Send + Sync + 'static,
+        B: HttpBody<Data = Bytes> + Send + 'static,
+        B::Error: Into<BoxError>,
+    {
+        self.router(service_provider).into_service()
+    }
+
     /// Handles standard I/O transport protocol
-    /// 
+    ///
     /// # Arguments
     /// * `service_provider` - A function that creates a service instance for the agent
-    pub async fn handle_stdio<S, F>(&mut self, service_provider: F) -> Result<(), Error>
+    pub async fn handle_stdio<S, F>(&mut self, service_provider: F) -> Result<()>
+    where
+        S: Service<RoleServer>,
+        F: Fn(Arc<Agent>) -> S + Send + Sync + 'static,
+    {
+        let provider = self.provider(service_provider);
+
+        provider().serve(stdio()).await.map_err(|e| {
+            println!("stdio error: {}", e);
+            Error::transport("stdio", e)
+        })?;
+
+        Ok(())
+    }
+}
+
+impl Server {
+    /// Mounts both transports, and the health and metrics routes, on one router
+    fn router<S, F>(&self, service_provider: F) -> Router
+    where
+        S: Service<RoleServer>,
+        F: Fn(Arc<Agent>) -> S + Send + Sync + 'static,
+    {
+        let provider = self.provider(service_provider);
+
+        let (sse_server, sse_router) = SseServer::new(self.sse_config());
+        sse_server.with_service(provider.clone());
+        let streamable = StreamableHttpService::new(
+            provider,
+            LocalSessionManager::default().into(),
+            self.streamable_config(),
+        );
+
+        let metrics = Arc::new(HttpMetrics::default());
+        let mut router =
+            self.authenticate(track(sse_router, &metrics, Transport::Sse).merge(track(
+                Router::new().nest_service(&self.config.streamable_path, streamable),
+                &metrics,
+                Transport::Streamable,
+            )));
+        if let Some(path) = &self.config.health_path {
+            router = router.route(path, get(|| async { "ok" }));
+        }
+        if let Some(path) = &self.config.metrics_path {
+            router = router.route(
+                path,
+                get(move || async move {
+                    (
+                        [(header::CONTENT_TYPE, "text/plain; version=0.0.4")],
+                        metrics.render(),
+                    )
+                }),
+            );
+        }
+        router
+    }
+
+    /// Returns the function creating the services of the sessions, their tool calls
+    /// go through the limiter of the server
+    fn provider<S, F>(
+        &self,
+        service_provider: F,
+    ) -> impl Fn() -> Limited<S> + Clone + Send + Sync + 'static
     where
         S: Service<RoleServer>,
         F: Fn(Arc<Agent>) -> S + Send + Sync + 'static,
     {
         let service_provider = Arc::new(service_provider);
         let agent = self.agent.clone();
-        let provider = {
-            let service_provider = service_provider.clone();
-            move || service_provider(agent.clone())
+        let limiter = self.limiter.clone();
+        move || Limited::new(service_provider(agent.clone()), limiter.clone())
+    }
+
+    /// Requires credentials on the routes when the server authenticates requests
+    fn authenticate(&self, router: Router) -> Router {
+        match &self.authenticator {
+            Some(authenticator) => router.layer(middleware::from_fn_with_state(
+                authenticator.clone(),
+                auth::require_auth,
+            )),
+            None => router,
+        }
+    }
+
+    fn sse_config(&self) -> SseServerConfig {
+        SseServerConfig {
+            bind: self.addr,
+            sse_path: self.config.sse_path.clone(),
+            post_path: self.config.message_path.clone(),
+            ct: self.ct.child_token(),
+            sse_keep_alive: self.config.keep_alive(),
+        }
+    }
+
+    fn streamable_config(&self) -> StreamableHttpServerConfig {
+        StreamableHttpServerConfig {
+            sse_keep_alive: self.config.keep_alive(),
+            ..Default::default()
+        }
+    }
+}
+
+/// Counts the requests of the transport routes
+fn track(router: Router, metrics: &Arc<HttpMetrics>, transport: Transport) -> Router {
+    let metrics = metrics.clone();
+    router.layer(middleware::from_fn(move |request: Request, next: Next| {
+        let metrics = metrics.clone();
+        async move { metrics.track(transport, next.run(request)).await }
+    }))
+}
+
+/// Resolves on ctrl-c or once the token is cancelled, and cancels the token so that
+/// everything bound to it stops
+async fn shutdown_signal(ct: CancellationToken) {
+    tokio::select! {
+        _ = tokio::signal::ctrl_c() => println!("ctrl-c received!"),
+        _ = ct.cancelled() => {}
+    }
+    ct.cancel();
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::agent::testing::{EchoServer, ScriptedLlm};
+    use reqwest::{Client, Response, StatusCode};
+    use std::time::Duration;
+
+    const INITIALIZE: &str = r#"{"jsonrpc":"2.0","id":1,"method":"initialize","params":{"protocolVersion":"2025-03-26","capabilities":{},"clientInfo":{"name":"test","version":"1.0"}}}"#;
+
+    /// Builds a server of an agent with the `[server]` table of the configuration
+    async fn server(config: &str) -> Server {
+        let echo = EchoServer::new(&["echo"]);
+        let config = format!("[mcp_servers.echo]\ncommand = \"echo\"\n{}", config);
+        let agent = Agent::with_echo_server(&config, &echo, ScriptedLlm::answering("done")).await;
+        Server::builder()
+            .agent(Arc::new(agent))
+            .build()
+            .await
+            .unwrap()
+    }
+
+    /// Serves the router on a local port and returns its base url
+    async fn serve(router: Router) -> String {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let router = router.into_make_service_with_connect_info::<SocketAddr>();
+        tokio::spawn(async move { axum::serve(listener, router).await });
+        format!("http://{}", addr)
+    }
+
+    /// Reads the body of a streamed response until it contains the text
+    async fn read_until(response: &mut Response, text: &str) -> String {
+        let mut body = String::new();
+        while !body.contains(text) {
+            let chunk = tokio::time::timeout(Duration::from_secs(5), response.chunk())
+                .await
+                .unwrap()
+                .unwrap()
+                .unwrap();
+            body.push_str(&String::from_utf8_lossy(&chunk));
+        }
+        body
+    }
+
+    #[tokio::test]
+    async fn serves_both_transports_and_open_routes_on_one_router() {
+        let server = server(
+            r#"
+            [server]
+            health_path = "/health"
+            metrics_path = "/metrics"
+            [server.auth.api_keys]
+            ci = "secret-key"
+            "#,
+        )
+        .await;
+        let url = serve(server.into_router(|_| EchoServer::new(&["echo"]))).await;
+        let client = Client::builder().no_proxy().build().unwrap();
+        let initialize = |key: &'static str| {
+            client
+                .post(format!("{}/mcp", url))
+                .bearer_auth(key)
+                .header(header::ACCEPT, "application/json, text/event-stream")
+                .header(header::CONTENT_TYPE, "application/json")
+                .body(INITIALIZE)
+                .send()
         };
 
-        provider()
-            .serve(stdio())
+        let response = client.get(format!("{}/sse", url)).send().await.unwrap();
+        assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+        assert_eq!(
+            initialize("wrong").await.unwrap().status(),
+            StatusCode::UNAUTHORIZED
+        );
+
+        let mut sse = client
+            .get(format!("{}/sse", url))
+            .bearer_auth("secret-key")
+            .send()
             .await
-            .inspect_err(|e| {
-                println!("stdio error: {}", e);
-            })
             .unwrap();
+        assert_eq!(sse.status(), StatusCode::OK);
+        assert!(
+            read_until(&mut sse, "sessionId=")
+                .await
+                .contains("/message?")
+        );
+        let mut response = initialize("secret-key").await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        assert!(
+            read_until(&mut response, "serverInfo")
+                .await
+                .contains(r#""id":1"#)
+        );
 
-        Ok(())
+        let health = client.get(format!("{}/health", url)).send().await.unwrap();
+        assert_eq!(health.status(), StatusCode::OK);
+        assert_eq!(health.text().await.unwrap(), "ok");
+        let metrics = client.get(format!("{}/metrics", url)).send().await.unwrap();
+        assert_eq!(metrics.status(), StatusCode::OK);
+        let metrics = metrics.text().await.unwrap();
+        assert!(metrics.contains("mcp_agent_http_requests_total{transport=\"sse\"} 1\n"));
+        assert!(metrics.contains("mcp_agent_http_requests_total{transport=\"streamable\"} 1\n"));
+    }
+
+    #[tokio::test]
+    async fn nested_router_advertises_the_prefixed_message_path() {
+        let server = server("").await;
+        let ct = server.cancellation_token();
+        let router =
+            Router::new().nest("/agent", server.into_router(|_| EchoServer::new(&["echo"])));
+        let url = serve(router).await;
+        let client = Client::builder().no_proxy().build().unwrap();
+
+        let mut sse = client
+            .get(format!("{}/agent/sse", url))
+            .send()
+            .await
+            .unwrap();
+        let body = read_until(&mut sse, "\n\n").await;
+        let endpoint = body
+            .lines()
+            .find_map(|line| line.strip_prefix("data: "))
+            .unwrap()
+            .to_string();
+        assert!(endpoint.starts_with("/agent/message?sessionId="));
+
+        let response = client
+            .post(format!("{}{}", url, endpoint))
+            .header(header::CONTENT_TYPE, "application/json")
+            .body(INITIALIZE)
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::ACCEPTED);
+        assert!(
+            read_until(&mut sse, "serverInfo")
+                .await
+                .contains("event: message")
+        );
+        ct.cancel();
+    }
+
+    #[test]
+    fn server_table_overrides_the_defaults() {
+        let config: ServerConfig = toml::from_str(
+            r#"
+            bind_address = "127.0.0.1:3000"
+            streamable_path = "/agent/mcp"
+            keep_alive_secs = 0
+            "#,
+        )
+        .unwrap();
+        assert_eq!(config.bind_address().unwrap().port(), 3000);
+        assert_eq!(config.sse_path, DEFAULT_SSE_PATH);
+        assert_eq!(config.keep_alive(), None);
+        assert!(config.validate().is_ok());
+
+        let config = ServerConfig {
+            message_path: "message".to_string(),
+            ..ServerConfig::default()
+        };
+        assert!(config.validate().is_err());
     }
 }
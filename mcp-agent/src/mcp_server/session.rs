@@ -0,0 +1,69 @@
+use crate::error::Result;
+use crate::llm::message::Conversation;
+use crate::session::SessionStore;
+use axum::http::request::Parts;
+use rmcp::RoleServer;
+use rmcp::service::RequestContext;
+
+const SESSION_ID_HEADER: &str = "mcp-session-id";
+
+/// Returns the MCP session id of the request
+///
+/// Only the streamable HTTP transport has sessions: the `Mcp-Session-Id` header
+/// assigned by its session manager is read from the request parts it stores in the
+/// extensions. Requests over stdio or SSE have none.
+pub fn mcp_session_id(context: &RequestContext<RoleServer>) -> Option<String> {
+    context
+        .extensions
+        .get::<Parts>()?
+        .headers
+        .get(SESSION_ID_HEADER)?
+        .to_str()
+        .ok()
+        .map(str::to_string)
+}
+
+/// A conversation bound to the MCP session of the client, so that it goes on over
+/// the tool calls of the session
+///
+/// Several conversations can share an MCP session, e.g. one per tool, each one is
+/// stored under `<mcp session id>/<name>`.
+pub struct McpSession {
+    session_id: Option<String>,
+    pub conversation: Conversation,
+}
+
+impl McpSession {
+    /// Loads the conversation of the MCP session of the request, or starts it with `new`
+    ///
+    /// Without MCP session the conversation starts with `new` and is never saved.
+    pub async fn load(
+        store: &dyn SessionStore,
+        context: &RequestContext<RoleServer>,
+        name: &str,
+        new: impl FnOnce() -> Conversation,
+    ) -> Result<Self> {
+        let session_id = mcp_session_id(context).map(|id| format!("{}/{}", id, name));
+        let conversation = match &session_id {
+            Some(session_id) => store.load(session_id).await?,
+            None => None,
+        };
+        Ok(Self {
+            session_id,
+            conversation: conversation.unwrap_or_else(new),
+        })
+    }
+
+    /// Returns the id the conversation is stored under, if it has an MCP session
+    pub fn session_id(&self) -> Option<&str> {
+        self.session_id.as_deref()
+    }
+
+    /// Saves the conversation, if it has an MCP session
+    pub async fn save(&self, store: &dyn SessionStore) -> Result<()> {
+        match &self.session_id {
+            Some(session_id) => store.save(session_id, &self.conversation).await,
+            None => Ok(()),
+        }
+    }
+}
@@ -0,0 +1,224 @@
+use super::store::SessionStore;
+use crate::error::{Error, Result};
+use crate::llm::message::Conversation;
+use async_trait::async_trait;
+use serde::{Deserialize, Serialize};
+use std::io::SeekFrom;
+use std::path::{Path, PathBuf};
+use tokio::fs::{self, File, OpenOptions};
+use tokio::io::{AsyncReadExt, AsyncSeekExt, AsyncWriteExt};
+use tokio::sync::Mutex;
+
+/// Appends the saved conversations to a JSON Lines file
+///
+/// Each line records a session id with its conversation, or `null` once deleted.
+/// The last line of a session wins, so loading reads the whole file: this store
+/// suits development and small deployments. A partial last line, left by a crash
+/// during a save, is ignored and removed by the next save.
+#[derive(Debug)]
+pub struct JsonlSessionStore {
+    path: PathBuf,
+    /// Serializes the appends of concurrent saves
+    lock: Mutex<()>,
+}
+
+/// A line of the file
+#[derive(Deserialize)]
+struct Entry {
+    session_id: String,
+    conversation: Option<Conversation>,
+}
+
+#[derive(Serialize)]
+struct EntryRef<'a> {
+    session_id: &'a str,
+    conversation: Option<&'a Conversation>,
+}
+
+impl JsonlSessionStore {
+    /// Creates a store writing to the file, created on the first save
+    pub fn new(path: impl AsRef<Path>) -> Self {
+        Self {
+            path: path.as_ref().to_path_buf(),
+            lock: Mutex::new(()),
+        }
+    }
+
+    async fn append(&self, entry: &EntryRef<'_>) -> Result<()> {
+        let mut line = serde_json::to_string(entry).map_err(Error::session)?;
+        line.push('\n');
+
+        let _guard = self.lock.lock().await;
+        let mut file = OpenOptions::new()
+            .create(true)
+            .read(true)
+            .append(true)
+            .open(&self.path)
+            .await
+            .map_err(|err| {
+                Error::session(format!("could not open {}: {}", self.path.display(), err))
+            })?;
+        truncate_partial_line(&mut file)
+            .await
+            .map_err(Error::session)?;
+        file.write_all(line.as_bytes())
+            .await
+            .map_err(Error::session)?;
+        file.flush().await.map_err(Error::session)
+    }
+}
+
+#[async_trait]
+impl SessionStore for JsonlSessionStore {
+    async fn load(&self, session_id: &str) -> Result<Option<Conversation>> {
+        let content = {
+            let _guard = self.lock.lock().await;
+            match fs::read_to_string(&self.path).await {
+                Ok(content) => content,
+                Err(err) if err.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+                Err(err) => {
+                    return Err(Error::session(format!(
+                        "could not read {}: {}",
+                        self.path.display(),
+                        err
+                    )));
+                }
+            }
+        };
+
+        let lines: Vec<_> = content.lines().collect();
+        let mut conversation = None;
+        for (index, line) in lines.iter().enumerate() {
+            if line.trim().is_empty() {
+                continue;
+            }
+            let entry: Entry = match serde_json::from_str(line) {
+                Ok(entry) => entry,
+                Err(err) if index + 1 == lines.len() && !content.ends_with('\n') => {
+                    println!(
+                        "warning: ignoring partial last line of {}: {}",
+                        self.path.display(),
+                        err
+                    );
+                    break;
+                }
+                Err(err) => {
+                    return Err(Error::session(format!(
+                        "invalid line {} of {}: {}",
+                        index + 1,
+                        self.path.display(),
+                        err
+                    )));
+                }
+            };
+            if entry.session_id == session_id {
+                conversation = entry.conversation;
+            }
+        }
+        Ok(conversation)
+    }
+
+    async fn save(&self, session_id: &str, conversation: &Conversation) -> Result<()> {
+        self.append(&EntryRef {
+            session_id,
+            conversation: Some(conversation),
+        })
+        .await
+    }
+
+    async fn delete(&self, session_id: &str) -> Result<()> {
+        self.append(&EntryRef {
+            session_id,
+            conversation: None,
+        })
+        .await
+    }
+}
+
+/// Removes the partial last line left by an interrupted save, so that the next save
+/// starts on a line of its own
+async fn truncate_partial_line(file: &mut File) -> std::io::Result<()> {
+    if file.metadata().await?.len() == 0 {
+        return Ok(());
+    }
+    file.seek(SeekFrom::End(-1)).await?;
+    let mut last = [0u8];
+    file.read_exact(&mut last).await?;
+    if last[0] == b'\n' {
+        return Ok(());
+    }
+
+    let mut content = Vec::new();
+    file.seek(SeekFrom::Start(0)).await?;
+    file.read_to_end(&mut content).await?;
+    let end = content
+        .iter()
+        .rposition(|byte| *byte == b'\n')
+        .map_or(0, |newline| newline + 1);
+    file.set_len(end as u64).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn conversation(answer: &str) -> Conversation {
+        let mut conversation = Conversation::new_with_prompt(256, "Be brief".to_string());
+        conversation.append_user_content("hello".to_string());
+        conversation.append_assistant_content(answer.to_string());
+        conversation
+    }
+
+    fn path(name: &str) -> PathBuf {
+        std::env::temp_dir().join(format!("mcp-agent-{}-{}.jsonl", name, std::process::id()))
+    }
+
+    #[tokio::test]
+    async fn saves_overwrites_and_reopens_conversations() {
+        let path = path("sessions");
+        let _ = fs::remove_file(&path).await;
+        let store = JsonlSessionStore::new(&path);
+
+        assert!(store.load("session").await.unwrap().is_none());
+        store.save("session", &conversation("hi")).await.unwrap();
+        store.save("other", &conversation("other")).await.unwrap();
+        store.save("session", &conversation("hello")).await.unwrap();
+        store.delete("other").await.unwrap();
+
+        let reopened = JsonlSessionStore::new(&path);
+        let loaded = reopened.load("session").await.unwrap().unwrap();
+        assert_eq!(loaded.messages, conversation("hello").messages);
+        assert!(reopened.load("other").await.unwrap().is_none());
+        fs::remove_file(&path).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn ignores_a_partial_last_line() {
+        let path = path("partial");
+        let _ = fs::remove_file(&path).await;
+        let store = JsonlSessionStore::new(&path);
+        store.save("session", &conversation("hi")).await.unwrap();
+        let mut file = OpenOptions::new().append(true).open(&path).await.unwrap();
+        file.write_all(br#"{"session_id":"session","conv"#)
+            .await
+            .unwrap();
+
+        let store = JsonlSessionStore::new(&path);
+        let loaded = store.load("session").await.unwrap().unwrap();
+        assert_eq!(loaded.messages, conversation("hi").messages);
+
+        store.save("session", &conversation("hello")).await.unwrap();
+        let loaded = store.load("session").await.unwrap().unwrap();
+        assert_eq!(loaded.messages, conversation("hello").messages);
+
+        let content = fs::read_to_string(&path).await.unwrap();
+        fs::write(&path, content.replacen("session", "corrupt\"", 1))
+            .await
+            .unwrap();
+        assert!(matches!(
+            store.load("session").await,
+            Err(Error::Session(message)) if message.starts_with("invalid line 1")
+        ));
+        fs::remove_file(&path).await.unwrap();
+    }
+}
@@ -0,0 +1,63 @@
+use super::store::SessionStore;
+use crate::error::Result;
+use crate::llm::message::Conversation;
+use async_trait::async_trait;
+use std::collections::HashMap;
+use std::sync::Mutex;
+
+/// Keeps the conversations in memory, they are lost when the process exits
+#[derive(Debug, Default)]
+pub struct MemorySessionStore {
+    sessions: Mutex<HashMap<String, Conversation>>,
+}
+
+impl MemorySessionStore {
+    /// Creates an empty store
+    pub fn new() -> Self {
+        Self::default()
+    }
+}
+
+#[async_trait]
+impl SessionStore for MemorySessionStore {
+    async fn load(&self, session_id: &str) -> Result<Option<Conversation>> {
+        let sessions = self.sessions.lock().unwrap_or_else(|err| err.into_inner());
+        Ok(sessions.get(session_id).cloned())
+    }
+
+    async fn save(&self, session_id: &str, conversation: &Conversation) -> Result<()> {
+        let mut sessions = self.sessions.lock().unwrap_or_else(|err| err.into_inner());
+        sessions.insert(session_id.to_string(), conversation.clone());
+        Ok(())
+    }
+
+    async fn delete(&self, session_id: &str) -> Result<()> {
+        let mut sessions = self.sessions.lock().unwrap_or_else(|err| err.into_inner());
+        sessions.remove(session_id);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn saves_overwrites_and_deletes_conversations() {
+        let store = MemorySessionStore::new();
+        let mut conversation = Conversation::new_with_prompt(256, "Be brief".to_string());
+        conversation.append_user_content("hello".to_string());
+
+        assert!(store.load("session").await.unwrap().is_none());
+        store.save("session", &conversation).await.unwrap();
+        conversation.append_assistant_content("hi".to_string());
+        store.save("session", &conversation).await.unwrap();
+
+        let loaded = store.load("session").await.unwrap().unwrap();
+        assert_eq!(loaded.messages, conversation.messages);
+        assert!(store.load("other").await.unwrap().is_none());
+
+        store.delete("session").await.unwrap();
+        assert!(store.load("session").await.unwrap().is_none());
+    }
+}
@@ -0,0 +1,8 @@
+pub mod store;
+pub use store::SessionStore;
+pub mod jsonl;
+pub use jsonl::JsonlSessionStore;
+pub mod memory;
+pub use memory::MemorySessionStore;
+pub mod sqlite;
+pub use sqlite::SqliteSessionStore;
@@ -0,0 +1,135 @@
+use super::store::SessionStore;
+use crate::error::{Error, Result};
+use crate::llm::message::Conversation;
+use async_trait::async_trait;
+use rusqlite::{Connection, OptionalExtension, params};
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+
+/// Keeps the conversations in a SQLite database, one row per session
+#[derive(Debug, Clone)]
+pub struct SqliteSessionStore {
+    connection: Arc<Mutex<Connection>>,
+}
+
+impl SqliteSessionStore {
+    /// Opens or creates the database file and its `sessions` table
+    pub fn open(path: impl AsRef<Path>) -> Result<Self> {
+        let connection = Connection::open(path.as_ref()).map_err(|err| {
+            Error::session(format!(
+                "could not open {}: {}",
+                path.as_ref().display(),
+                err
+            ))
+        })?;
+        Self::with_connection(connection)
+    }
+
+    /// Creates a store in a database kept in memory
+    pub fn open_in_memory() -> Result<Self> {
+        Self::with_connection(Connection::open_in_memory().map_err(Error::session)?)
+    }
+
+    fn with_connection(connection: Connection) -> Result<Self> {
+        connection
+            .execute_batch(
+                "CREATE TABLE IF NOT EXISTS sessions (
+                    session_id TEXT PRIMARY KEY,
+                    conversation TEXT NOT NULL,
+                    updated_at INTEGER NOT NULL DEFAULT (unixepoch())
+                )",
+            )
+            .map_err(Error::session)?;
+        Ok(Self {
+            connection: Arc::new(Mutex::new(connection)),
+        })
+    }
+
+    /// Runs a query on a blocking thread, SQLite calls block
+    async fn run<T, F>(&self, query: F) -> Result<T>
+    where
+        T: Send + 'static,
+        F: FnOnce(&Connection) -> rusqlite::Result<T> + Send + 'static,
+    {
+        let connection = self.connection.clone();
+        tokio::task::spawn_blocking(move || {
+            let connection = connection.lock().unwrap_or_else(|err| err.into_inner());
+            query(&connection).map_err(Error::session)
+        })
+        .await
+        .map_err(Error::session)?
+    }
+}
+
+#[async_trait]
+impl SessionStore for SqliteSessionStore {
+    async fn load(&self, session_id: &str) -> Result<Option<Conversation>> {
+        let session_id = session_id.to_string();
+        let conversation = self
+            .run(move |connection| {
+                connection
+                    .query_row(
+                        "SELECT conversation FROM sessions WHERE session_id = ?1",
+                        params![session_id],
+                        |row| row.get::<_, String>(0),
+                    )
+                    .optional()
+            })
+            .await?;
+
+        conversation
+            .map(|conversation| serde_json::from_str(&conversation).map_err(Error::session))
+            .transpose()
+    }
+
+    async fn save(&self, session_id: &str, conversation: &Conversation) -> Result<()> {
+        let session_id = session_id.to_string();
+        let conversation = serde_json::to_string(conversation).map_err(Error::session)?;
+        self.run(move |connection| {
+            connection.execute(
+                "INSERT INTO sessions (session_id, conversation) VALUES (?1, ?2)
+                 ON CONFLICT (session_id) DO UPDATE
+                 SET conversation = excluded.conversation, updated_at = unixepoch()",
+                params![session_id, conversation],
+            )
+        })
+        .await?;
+        Ok(())
+    }
+
+    async fn delete(&self, session_id: &str) -> Result<()> {
+        let session_id = session_id.to_string();
+        self.run(move |connection| {
+            connection.execute(
+                "DELETE FROM sessions WHERE session_id = ?1",
+                params![session_id],
+            )
+        })
+        .await?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn saves_loads_and_deletes_conversations() {
+        let store = SqliteSessionStore::open_in_memory().unwrap();
+        let mut conversation = Conversation::new_with_prompt(256, "Be brief".to_string());
+        conversation.append_user_content("hello".to_string());
+
+        assert!(store.load("session").await.unwrap().is_none());
+        store.save("session", &conversation).await.unwrap();
+        conversation.append_assistant_content("hi".to_string());
+        store.save("session", &conversation).await.unwrap();
+
+        let loaded = store.load("session").await.unwrap().unwrap();
+        assert_eq!(loaded.messages, conversation.messages);
+        assert!(store.load("other").await.unwrap().is_none());
+
+        store.delete("session").await.unwrap();
+        assert!(store.load("session").await.unwrap().is_none());
+    }
+}
@@ -0,0 +1,17 @@
+use crate::error::Result;
+use crate::llm::message::Conversation;
+use async_trait::async_trait;
+
+/// Saves and loads conversations by session id, so that a conversation can go on
+/// over several requests
+#[async_trait]
+pub trait SessionStore: Send + Sync {
+    /// Returns the conversation of the session, `None` if it was never saved
+    async fn load(&self, session_id: &str) -> Result<Option<Conversation>>;
+
+    /// Saves the conversation of the session, replacing the previous one
+    async fn save(&self, session_id: &str, conversation: &Conversation) -> Result<()>;
+
+    /// Deletes the conversation of the session, if any
+    async fn delete(&self, session_id: &str) -> Result<()>;
+}